@@ -66,7 +66,8 @@ namespace LanKM
                 RedirectStandardOutput = true,
                 RedirectStandardInput = true,
                 CreateNoWindow = true,
-                Arguments = "server 6000",
+                // Port, hotkeys and the rest come from lankm's config file
+                Arguments = "server",
             };
 
             try
@@ -140,7 +140,8 @@ public class ServerPage : Gtk.Box {
         try {
             // Placeholder ping to test the server_log scrolling
             log ("Starting " + lankm_headless_path);
-            child_process = new GLib.Subprocess (flags, lankm_headless_path);
+            // Port, hotkeys and the rest come from lankm's config file
+            child_process = new GLib.Subprocess (flags, lankm_headless_path, "server");
             log_update.begin();
         } catch (Error e) {
             log ("Error spawning child process: " + (string)e);
@@ -0,0 +1,446 @@
+use std::io;
+use std::net::{self, IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::KeyboardLayout;
+use crate::event::{
+    self, Capabilities, DecodeError, HandshakeError, Hello, KeyEvent, KeyEventKind, KeysymEvent,
+    Message, MessageReader, Modifiers, UsagePage, HID_CAPS_LOCK, HID_NUM_LOCK, HID_SCROLL_LOCK,
+};
+use crate::input_injection;
+use crate::pairing;
+use crate::peers::{self, Peer, PeerStore};
+use crate::tls::{self, Identity, IdentityPaths, Stream};
+
+/// Name the client certificate is stored under in the config directory
+pub const IDENTITY_NAME: &str = "client";
+
+pub struct ClientOptions {
+    pub address: net::Ipv4Addr,
+    pub port: u16,
+    pub name: String,
+    /// Only trust a server with this certificate, not just any paired one
+    pub fingerprint: Option<String>,
+    pub plaintext: bool,
+    /// The server pings regularly, a silence this long means it's gone
+    pub heartbeat_timeout: Duration,
+    /// Keys still held after a silence this long are released, their
+    /// release may be stuck in the network
+    pub stuck_key_timeout: Duration,
+    pub key_repeat: RepeatPolicy,
+    pub key_mode: KeyMode,
+    /// Layout to type with instead of the one configured on this machine
+    pub keyboard_layout: Option<KeyboardLayout>,
+    pub identity: IdentityPaths,
+}
+
+/// How keys are sent, `--key-mode`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyMode {
+    /// By position, the key types whatever it types in this machine's layout
+    Scancode,
+    /// By what the key types in the server's layout, looked up in this
+    /// machine's layout
+    Keysym,
+}
+
+impl FromStr for KeyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scancode" => Ok(KeyMode::Scancode),
+            "keysym" => Ok(KeyMode::Keysym),
+            _ => Err(format!("'{}' is not scancode or keysym", s)),
+        }
+    }
+}
+
+/// Who repeats a held key, `--key-repeat`. On Linux only the console sees
+/// forwarded or generated repeats, libinput and Xorg drop them and repeat
+/// held keys on their own.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RepeatPolicy {
+    /// Forward the repeats of the server's keyboard
+    Forward,
+    /// Repeat here, starting after `delay` and then every `interval`
+    Generate { delay: Duration, interval: Duration },
+    /// Leave it to the desktop, most repeat held keys on their own
+    Off,
+}
+
+/// Longest delay before a held key repeats, keyboards go up to about 1 s
+const MAX_REPEAT_DELAY_MS: u64 = 10_000;
+
+impl FromStr for RepeatPolicy {
+    type Err = String;
+
+    // `forward`, `off` or `DELAY:RATE`, e.g. `500:25` for a 500 ms delay and
+    // 25 repeats per second
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward" => return Ok(RepeatPolicy::Forward),
+            "off" => return Ok(RepeatPolicy::Off),
+            _ => {}
+        }
+
+        let invalid = || format!("'{}' is not forward, off or DELAY:RATE", s);
+        let (delay, rate) = s.split_once(':').ok_or_else(invalid)?;
+        let delay: u64 = delay.parse().map_err(|_| invalid())?;
+        let rate: u32 = rate.parse().map_err(|_| invalid())?;
+        if delay > MAX_REPEAT_DELAY_MS {
+            return Err(format!(
+                "the repeat delay must be at most {} ms",
+                MAX_REPEAT_DELAY_MS
+            ));
+        }
+        if rate == 0 {
+            return Err("the repeat rate must be at least 1 per second".to_owned());
+        }
+
+        Ok(RepeatPolicy::Generate {
+            delay: Duration::from_millis(delay),
+            interval: Duration::from_secs(1) / rate,
+        })
+    }
+}
+
+/// Repeats the last pressed key for `RepeatPolicy::Generate`, like a
+/// keyboard does
+struct Repeater {
+    delay: Duration,
+    interval: Duration,
+    /// The repeat to send, its keysym in keysym mode, and when
+    next: Option<(KeyEvent, Option<u32>, Instant)>,
+}
+
+impl Repeater {
+    fn key(&mut self, event: KeyEvent, keysym: Option<u32>) {
+        match event.kind {
+            KeyEventKind::Press if repeats(&event) => {
+                let repeat = KeyEvent {
+                    kind: KeyEventKind::Repeat,
+                    ..event
+                };
+                self.next = Some((repeat, keysym, Instant::now() + self.delay));
+            }
+            KeyEventKind::Release if self.next.is_some_and(|(r, _, _)| r.same_key(&event)) => {
+                self.stop();
+            }
+            _ => {}
+        }
+    }
+
+    fn stop(&mut self) {
+        self.next = None;
+    }
+
+    fn until_next(&self) -> Option<Duration> {
+        self.next
+            .map(|(_, _, at)| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// The repeat to send now, if one is due
+    fn due(&mut self) -> Option<(KeyEvent, Option<u32>)> {
+        let (repeat, keysym, at) = self.next.as_mut()?;
+        if Instant::now() < *at {
+            return None;
+        }
+
+        *at += self.interval;
+        Some((*repeat, *keysym))
+    }
+}
+
+fn emit_key(injector: &mut input_injection::InputInjector, key: KeyEvent, keysym: Option<u32>) {
+    match keysym {
+        Some(keysym) => injector.emit_keysym(KeysymEvent { keysym, key }),
+        None => injector.emit(key),
+    }
+}
+
+/// Modifiers and locks don't repeat, same as on a real keyboard
+fn repeats(event: &KeyEvent) -> bool {
+    event.page != UsagePage::Keyboard
+        || (Modifiers::from_hid(event.hid).is_none()
+            && ![HID_CAPS_LOCK, HID_NUM_LOCK, HID_SCROLL_LOCK].contains(&event.hid))
+}
+
+fn connect_to_server(
+    options: &ClientOptions,
+    tls_config: Option<&Arc<rustls::ClientConfig>>,
+) -> Stream {
+    let address = IpAddr::V4(options.address);
+    let socket = SocketAddr::new(address, options.port);
+    loop {
+        log::info!("Trying to connect");
+        let mut stream = match connect(&socket, tls_config) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::info!("Could not connect ({}) Retrying...", e);
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+
+        let mut caps = Capabilities::all();
+        if !matches!(options.key_repeat, RepeatPolicy::Forward) {
+            caps.remove(Capabilities::KEY_REPEAT);
+        }
+        if options.key_mode == KeyMode::Scancode {
+            caps.remove(Capabilities::KEYSYMS);
+        }
+
+        match handshake(&mut stream, &options.name, caps) {
+            Ok((ack, server)) => {
+                log::info!("Connected to server {}", server.name);
+                log::debug!("Server capabilities: {:?}", ack.caps);
+                return stream;
+            }
+            Err(e @ HandshakeError::VersionMismatch(_)) => {
+                log::error!(
+                    "Can't talk to the server, {}. Please update both sides to the same lankm release",
+                    e
+                );
+                std::process::exit(1);
+            }
+            Err(e @ HandshakeError::NotPaired(_)) => {
+                log::error!(
+                    "Handshake failed, {}. Run `lankm-headless pair` on the server and `lankm-headless pair-with` here",
+                    e
+                );
+                std::process::exit(1);
+            }
+            Err(e @ HandshakeError::IdentityChanged(_)) => {
+                log::error!("Refusing to connect, {}", e);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                log::error!("Handshake failed ({}) Retrying...", e);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn connect(
+    socket: &SocketAddr,
+    tls_config: Option<&Arc<rustls::ClientConfig>>,
+) -> io::Result<Stream> {
+    let tcp = net::TcpStream::connect_timeout(socket, Duration::from_secs(2))?;
+    tcp.set_read_timeout(Some(crate::HANDSHAKE_TIMEOUT))?;
+
+    tls::connect(tcp, socket.ip(), tls_config)
+}
+
+/// Returns the server's HelloAck and who it is among the known hosts
+fn handshake(
+    stream: &mut Stream,
+    name: &str,
+    caps: Capabilities,
+) -> Result<(Hello, Peer), HandshakeError> {
+    stream
+        .tcp()
+        .set_read_timeout(Some(crate::HANDSHAKE_TIMEOUT))?;
+    let ack = event::client_handshake(stream, name, caps)?;
+
+    // Reloaded on every attempt, pairing may have happened in the meantime
+    let mut peers = PeerStore::load(peers::SERVERS_FILE)?;
+    let server = peers.identify(&ack.name, stream.peer_fingerprint().as_deref())?;
+
+    pairing::client_authenticate(stream, &server.key)?;
+    stream.tcp().set_read_timeout(None)?;
+
+    Ok((ack, server))
+}
+
+pub fn run_client(options: ClientOptions) {
+    if options.plaintext {
+        log::warn!(
+            "Connecting without encryption, keystrokes can be read by anyone on the network"
+        );
+    }
+
+    match PeerStore::load(peers::SERVERS_FILE) {
+        Ok(peers) if peers.is_empty() => {
+            log::error!("Not paired with any server, run `lankm-headless pair` on the server and `lankm-headless pair-with` here");
+            std::process::exit(1);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("Could not load the paired servers: {}", e),
+    }
+
+    let tls_config = if options.plaintext {
+        None
+    } else {
+        match Identity::load_or_generate(&options.identity) {
+            Ok(identity) => Some(tls::client_config(
+                &identity,
+                options.fingerprint.as_deref(),
+            )),
+            Err(e) => {
+                log::error!("Could not load the client certificate: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let mut injector = input_injection::InputInjector::new(options.keyboard_layout.clone());
+    let mut stream: Option<Stream> = None;
+    let mut reader = MessageReader::default();
+    let mut last_heard = Instant::now();
+    let mut repeater = match options.key_repeat {
+        RepeatPolicy::Generate { delay, interval } => Some(Repeater {
+            delay,
+            interval,
+            next: None,
+        }),
+        RepeatPolicy::Forward | RepeatPolicy::Off => None,
+    };
+
+    loop {
+        let s = match stream.as_mut() {
+            Some(s) => s,
+            None => {
+                stream = Some(connect_to_server(&options, tls_config.as_ref()));
+                reader = MessageReader::default();
+                last_heard = Instant::now();
+                stream.as_mut().unwrap()
+            }
+        };
+
+        if let Some((repeat, keysym)) = repeater.as_mut().and_then(Repeater::due) {
+            emit_key(&mut injector, repeat, keysym);
+        }
+
+        if injector.is_holding() && last_heard.elapsed() >= options.stuck_key_timeout {
+            log::warn!(
+                "Nothing heard from the server for {:.1}s, releasing {}",
+                last_heard.elapsed().as_secs_f32(),
+                injector.held().join(", ")
+            );
+            if let Some(repeater) = repeater.as_mut() {
+                repeater.stop();
+            }
+            injector.release_all();
+        }
+
+        // Wake up for the next repeat, when held keys count as stuck, or
+        // when the heartbeat is overdue
+        let mut timeout = options
+            .heartbeat_timeout
+            .saturating_sub(last_heard.elapsed());
+        if let Some(until) = repeater.as_ref().and_then(Repeater::until_next) {
+            timeout = timeout.min(until);
+        }
+        if injector.is_holding() {
+            timeout = timeout.min(
+                options
+                    .stuck_key_timeout
+                    .saturating_sub(last_heard.elapsed()),
+            );
+        }
+        let read = s
+            .tcp()
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+            .and_then(|_| reader.read_from(s));
+        if let Ok(Some(_)) = read {
+            last_heard = Instant::now();
+        }
+
+        let key = match read {
+            Ok(Some(Message::Key(event))) => Some((event, None)),
+            Ok(Some(Message::Keysym(event))) => Some((event.key, Some(event.keysym))),
+            _ => None,
+        };
+        if let Some((event, keysym)) = key {
+            match repeater.as_mut() {
+                // Not asked for, we repeat keys ourselves
+                Some(_) if event.kind == KeyEventKind::Repeat => {}
+                Some(repeater) => {
+                    repeater.key(event, keysym);
+                    emit_key(&mut injector, event, keysym);
+                }
+                None => emit_key(&mut injector, event, keysym),
+            }
+        }
+
+        let error = match read {
+            Ok(Some(Message::Key(_) | Message::Keysym(_))) => None,
+            Ok(Some(Message::Pointer(event))) => {
+                injector.emit_pointer(event);
+                None
+            }
+            Ok(Some(Message::State(state))) => {
+                if let Some(repeater) = repeater.as_mut() {
+                    repeater.stop();
+                }
+                injector.sync_state(&state);
+                None
+            }
+            Ok(Some(Message::Text(text))) => {
+                injector.emit_text(&text);
+                None
+            }
+            Ok(Some(Message::Leave)) => {
+                if let Some(repeater) = repeater.as_mut() {
+                    repeater.stop();
+                }
+                injector.release_all();
+                None
+            }
+            Ok(Some(Message::Ping)) => Message::Pong.write_to(s).err(),
+            Ok(Some(m)) => {
+                log::warn!("Unexpected message from server: {:?}", m);
+                None
+            }
+            Ok(None) if last_heard.elapsed() >= options.heartbeat_timeout => Some(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "missed the server's heartbeat",
+            )),
+            Ok(None) => None,
+            Err(e) if DecodeError::from_io(&e).is_some() => {
+                log::warn!("Dropping a bad message from the server: {}", e);
+                None
+            }
+            Err(e) => Some(e),
+        };
+
+        if let Some(e) = error {
+            log::error!("Lost the connection to the server: {}", e);
+            stream = None;
+            log::error!("Connection closed");
+            if let Some(repeater) = repeater.as_mut() {
+                repeater.stop();
+            }
+            injector.release_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeat_policies() {
+        assert_eq!("forward".parse(), Ok(RepeatPolicy::Forward));
+        assert_eq!("off".parse(), Ok(RepeatPolicy::Off));
+        assert_eq!(
+            "500:25".parse(),
+            Ok(RepeatPolicy::Generate {
+                delay: Duration::from_millis(500),
+                interval: Duration::from_millis(40),
+            })
+        );
+        assert!("10000:1".parse::<RepeatPolicy>().is_ok());
+
+        for invalid in ["", "500", "500:", ":25", "500:0", "10001:25", "-1:25", "on"] {
+            assert!(invalid.parse::<RepeatPolicy>().is_err(), "{}", invalid);
+        }
+        assert!("18446744073709551615:25".parse::<RepeatPolicy>().is_err());
+    }
+}
@@ -0,0 +1,334 @@
+// The config file, config.toml in the config directory unless `--config`
+// points elsewhere. Everything in it can also be given on the command line,
+// which wins over the file. A running server picks up changed hotkeys and
+// device filters on SIGHUP or a `reload` line on stdin.
+//
+//     [server]
+//     port = 6000
+//     bind = "0.0.0.0"
+//     hotkeys = ["ctrl+alt+tab=next"]
+//     screens = ["server=2560x1440"]
+//     neighbors = ["laptop:left:server"]
+//     heartbeat-interval = 1
+//     heartbeat-timeout = 5
+//
+//     [server.devices]
+//     include = ["Keychron", "id:046d:c52b"]
+//     exclude = ["phys:usb-0000:00:14.0-4/input1"]
+//     deny = ["Yubico", "path:/dev/input/by-id/usb-Honeywell_Scanner-event-kbd"]
+//
+//     [server.tls]
+//     cert = "/etc/lankm/server.crt"
+//     key = "/etc/lankm/server.key"
+//
+//     [server.clients.laptop]
+//     screen = "1920x1200"
+//     position = "left:server"
+//     hotkey = "ctrl+alt+1"
+//
+//     [client]
+//     keyboard-layout = "de(nodeadkeys)"
+//     address = "192.168.1.10"
+//     port = 6000
+//     key-repeat = "500:25"
+//     key-mode = "keysym"
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::client::{KeyMode, RepeatPolicy};
+use crate::device_filter::DeviceFilters;
+use crate::hotkey::HotkeyBinding;
+use crate::layout::{NeighborArg, ScreenArg};
+use crate::tls::IdentityPaths;
+
+/// Name of the config file in the config directory
+pub const FILE_NAME: &str = "config.toml";
+
+/// Port of the server when neither the command line nor the config file
+/// gives one
+pub const DEFAULT_PORT: u16 = 6000;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub client: ClientConfig,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    pub port: Option<u16>,
+    /// Address to listen on, all of them by default
+    pub bind: Option<IpAddr>,
+    #[serde(deserialize_with = "parsed_list")]
+    pub hotkeys: Vec<HotkeyBinding>,
+    #[serde(deserialize_with = "parsed_list")]
+    pub screens: Vec<ScreenArg>,
+    #[serde(deserialize_with = "parsed_list")]
+    pub neighbors: Vec<NeighborArg>,
+    pub allow_plaintext: Option<bool>,
+    #[serde(deserialize_with = "seconds")]
+    pub heartbeat_interval: Option<Duration>,
+    #[serde(deserialize_with = "seconds")]
+    pub heartbeat_timeout: Option<Duration>,
+    pub devices: DeviceFilters,
+    /// Layout keys are turned into keysyms with for clients in keysym mode
+    #[serde(deserialize_with = "parsed")]
+    pub keyboard_layout: Option<KeyboardLayout>,
+    pub tls: TlsPaths,
+    /// Settings of each client, by name
+    pub clients: BTreeMap<String, ClientSettings>,
+}
+
+/// What the server knows about one client, a shorter way to write its
+/// screen, neighbor and hotkey
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    /// WIDTHxHEIGHT
+    pub screen: Option<String>,
+    /// DIRECTION:OTHER, e.g. `left:server`
+    pub position: Option<String>,
+    /// Keys switching to the client, e.g. `ctrl+alt+1`
+    pub hotkey: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ClientConfig {
+    pub address: Option<Ipv4Addr>,
+    pub port: Option<u16>,
+    pub name: Option<String>,
+    pub fingerprint: Option<String>,
+    pub plaintext: Option<bool>,
+    #[serde(deserialize_with = "seconds")]
+    pub heartbeat_timeout: Option<Duration>,
+    #[serde(deserialize_with = "seconds")]
+    pub stuck_key_timeout: Option<Duration>,
+    #[serde(deserialize_with = "parsed")]
+    pub key_repeat: Option<RepeatPolicy>,
+    #[serde(deserialize_with = "parsed")]
+    pub key_mode: Option<KeyMode>,
+    /// Layout text and keysyms are typed with
+    #[serde(deserialize_with = "parsed")]
+    pub keyboard_layout: Option<KeyboardLayout>,
+    pub tls: TlsPaths,
+}
+
+/// An XKB layout and variant, e.g. `de` or `de(nodeadkeys)`, for when the
+/// one lankm finds isn't the one in use
+#[derive(Clone, PartialEq, Debug)]
+pub struct KeyboardLayout {
+    pub layout: String,
+    pub variant: Option<String>,
+}
+
+impl FromStr for KeyboardLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid keyboard layout '{}', expected LAYOUT[(VARIANT)]",
+                s
+            )
+        };
+        let valid = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-,".contains(c))
+        };
+
+        let (layout, variant) = match s.strip_suffix(')') {
+            Some(rest) => {
+                let (layout, variant) = rest.split_once('(').ok_or_else(invalid)?;
+                (layout, Some(variant))
+            }
+            None => (s, None),
+        };
+        if !valid(layout) || !variant.is_none_or(valid) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            layout: layout.to_owned(),
+            variant: variant.map(str::to_owned),
+        })
+    }
+}
+
+impl fmt::Display for KeyboardLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.layout)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "({})", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where to keep a certificate and its key instead of the config directory
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsPaths {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl TlsPaths {
+    /// Paths for the identity stored under `name`, the config directory
+    /// for whatever isn't set
+    pub fn identity(&self, name: &str) -> IdentityPaths {
+        let default = IdentityPaths::named(name);
+
+        IdentityPaths {
+            cert: self.cert.clone().unwrap_or(default.cert),
+            key: self.key.clone().unwrap_or(default.key),
+        }
+    }
+}
+
+/// `[server.clients]` as screens, neighbors and hotkeys
+#[derive(Default)]
+pub struct ClientEntries {
+    pub screens: Vec<ScreenArg>,
+    pub neighbors: Vec<NeighborArg>,
+    pub hotkeys: Vec<HotkeyBinding>,
+}
+
+impl ServerConfig {
+    pub fn client_entries(&self) -> Result<ClientEntries, String> {
+        let mut entries = ClientEntries::default();
+
+        for (name, settings) in &self.clients {
+            let invalid = |e| format!("client {}: {}", name, e);
+            if let Some(screen) = &settings.screen {
+                entries
+                    .screens
+                    .push(format!("{}={}", name, screen).parse().map_err(invalid)?);
+            }
+            if let Some(position) = &settings.position {
+                entries
+                    .neighbors
+                    .push(format!("{}:{}", name, position).parse().map_err(invalid)?);
+            }
+            if let Some(hotkey) = &settings.hotkey {
+                entries
+                    .hotkeys
+                    .push(format!("{}={}", hotkey, name).parse().map_err(invalid)?);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+impl Config {
+    /// Loads the config file at `path`, or the one in the config directory.
+    /// Only a missing file in the config directory is fine, that's an empty
+    /// config.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let default_path = crate::config_dir().join(FILE_NAME);
+        let path_buf = path.unwrap_or(&default_path).to_path_buf();
+        let contents = match fs::read_to_string(&path_buf) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && path.is_none() => {
+                return Ok(Self::default())
+            }
+            Err(e) => return Err(ConfigError::Io(path_buf, e)),
+        };
+
+        let config: Self =
+            toml::from_str(&contents).map_err(|e| ConfigError::Parse(path_buf.clone(), e))?;
+        config
+            .server
+            .client_entries()
+            .map_err(|e| ConfigError::Invalid(path_buf, e))?;
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Invalid(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A setting written the way its command line flag takes it
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Same as `parsed`, for a list
+pub fn parsed_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn seconds<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs = f64::deserialize(deserializer)?;
+    crate::seconds(secs)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyboard_layouts() {
+        let layout: KeyboardLayout = "de(nodeadkeys)".parse().unwrap();
+        assert_eq!(layout.layout, "de");
+        assert_eq!(layout.variant.as_deref(), Some("nodeadkeys"));
+        assert_eq!(layout.to_string(), "de(nodeadkeys)");
+
+        let layout: KeyboardLayout = "us,ru".parse().unwrap();
+        assert_eq!(layout.variant, None);
+
+        for invalid in ["", "de(", "de()", "(nodeadkeys)", "de(x)(y)", "de x"] {
+            assert!(invalid.parse::<KeyboardLayout>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// What an input device can be picked by
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    /// e.g. `/dev/input/event3`
+    pub path: PathBuf,
+    /// Where the device is plugged in, e.g. `usb-0000:00:14.0-2/input0`
+    pub phys: Option<String>,
+    pub vendor: u16,
+    pub product: u16,
+}
+
+/// A device pattern: `id:VENDOR[:PRODUCT]` in hex, `path:PATH`, `phys:PHYS`,
+/// or `name:NAME` and anything else matching part of the device name
+#[derive(Clone, Debug)]
+pub enum DevicePattern {
+    Name(String),
+    /// Device node or a link to it, like the ones in /dev/input/by-id
+    Path(PathBuf),
+    Phys(String),
+    Id {
+        vendor: u16,
+        product: Option<u16>,
+    },
+}
+
+impl FromStr for DevicePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = |id: &str| {
+            u16::from_str_radix(id, 16).map_err(|_| {
+                format!(
+                    "invalid device id '{}', expected VENDOR[:PRODUCT] in hex",
+                    s
+                )
+            })
+        };
+
+        match s.split_once(':') {
+            Some(("name", name)) => Ok(DevicePattern::Name(name.to_owned())),
+            Some(("path", path)) => Ok(DevicePattern::Path(path.into())),
+            Some(("phys", phys)) => Ok(DevicePattern::Phys(phys.to_owned())),
+            Some(("id", id)) => match id.split_once(':') {
+                Some((vendor, product)) => Ok(DevicePattern::Id {
+                    vendor: hex(vendor)?,
+                    product: Some(hex(product)?),
+                }),
+                None => Ok(DevicePattern::Id {
+                    vendor: hex(id)?,
+                    product: None,
+                }),
+            },
+            _ if s.is_empty() => Err("empty device pattern".to_owned()),
+            _ => Ok(DevicePattern::Name(s.to_owned())),
+        }
+    }
+}
+
+impl DevicePattern {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        match self {
+            DevicePattern::Name(name) => device.name.contains(name.as_str()),
+            DevicePattern::Path(path) => same_file(path, &device.path),
+            DevicePattern::Phys(phys) => device.phys.as_deref() == Some(phys.as_str()),
+            DevicePattern::Id { vendor, product } => {
+                device.vendor == *vendor && product.is_none_or(|p| device.product == p)
+            }
+        }
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Which input devices the server captures. Without includes every keyboard
+/// and mouse is captured, excludes win over includes. The denylist is like
+/// the excludes, but command line flags add to it instead of replacing it.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceFilters {
+    #[serde(deserialize_with = "crate::config::parsed_list")]
+    pub include: Vec<DevicePattern>,
+    #[serde(deserialize_with = "crate::config::parsed_list")]
+    pub exclude: Vec<DevicePattern>,
+    #[serde(deserialize_with = "crate::config::parsed_list")]
+    pub deny: Vec<DevicePattern>,
+}
+
+impl DeviceFilters {
+    pub fn allows(&self, device: &DeviceInfo) -> bool {
+        let matches = |pattern: &DevicePattern| pattern.matches(device);
+
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+            && !self.deny.iter().any(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keychron() -> DeviceInfo {
+        DeviceInfo {
+            name: "Keychron K2 Keyboard".to_owned(),
+            path: "/dev/input/event3".into(),
+            phys: Some("usb-0000:00:14.0-2/input0".to_owned()),
+            vendor: 0x05ac,
+            product: 0x024f,
+        }
+    }
+
+    fn matches(pattern: &str) -> bool {
+        pattern
+            .parse::<DevicePattern>()
+            .unwrap()
+            .matches(&keychron())
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<DevicePattern> {
+        patterns.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_patterns() {
+        assert!(matches!(
+            "id:046d:C52B".parse(),
+            Ok(DevicePattern::Id {
+                vendor: 0x046d,
+                product: Some(0xc52b)
+            })
+        ));
+        assert!(matches!(
+            "id:046d".parse(),
+            Ok(DevicePattern::Id {
+                vendor: 0x046d,
+                product: None
+            })
+        ));
+        assert!(matches!(
+            "phys:usb-0000:00:14.0-4/input1".parse(),
+            Ok(DevicePattern::Phys(phys)) if phys == "usb-0000:00:14.0-4/input1"
+        ));
+        assert!(matches!(
+            "path:/dev/input/by-id/usb-kbd".parse(),
+            Ok(DevicePattern::Path(path)) if path == Path::new("/dev/input/by-id/usb-kbd")
+        ));
+        // Names may contain colons, only the known prefixes are special
+        assert!(matches!(
+            "name:id:1".parse(),
+            Ok(DevicePattern::Name(name)) if name == "id:1"
+        ));
+        assert!(matches!(
+            "Logitech: G502".parse(),
+            Ok(DevicePattern::Name(name)) if name == "Logitech: G502"
+        ));
+
+        for invalid in [
+            "",
+            "id:",
+            "id:xyz",
+            "id:10000",
+            "id:046d:",
+            "id:046d:c52b:1",
+        ] {
+            assert!(invalid.parse::<DevicePattern>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn matches_devices() {
+        assert!(matches("Keychron"));
+        assert!(matches("name:K2"));
+        assert!(!matches("keychron"));
+        assert!(matches("id:05ac"));
+        assert!(matches("id:05ac:024f"));
+        assert!(!matches("id:05ac:0250"));
+        assert!(!matches("id:046d"));
+        assert!(matches("phys:usb-0000:00:14.0-2/input0"));
+        assert!(!matches("phys:usb-0000:00:14.0-2"));
+        assert!(matches("path:/dev/input/event3"));
+        assert!(!matches("path:/dev/input/event4"));
+
+        let without_phys = DeviceInfo {
+            phys: None,
+            ..keychron()
+        };
+        assert!(!"phys:"
+            .parse::<DevicePattern>()
+            .unwrap()
+            .matches(&without_phys));
+    }
+
+    #[test]
+    fn filters_devices() {
+        let device = keychron();
+        assert!(DeviceFilters::default().allows(&device));
+
+        let filters = DeviceFilters {
+            include: patterns(&["Logitech", "id:05ac"]),
+            ..Default::default()
+        };
+        assert!(filters.allows(&device));
+
+        let filters = DeviceFilters {
+            include: patterns(&["Logitech"]),
+            ..Default::default()
+        };
+        assert!(!filters.allows(&device));
+
+        let filters = DeviceFilters {
+            include: patterns(&["Keychron"]),
+            exclude: patterns(&["id:05ac:024f"]),
+            ..Default::default()
+        };
+        assert!(!filters.allows(&device));
+
+        let filters = DeviceFilters {
+            deny: patterns(&["K2"]),
+            ..Default::default()
+        };
+        assert!(!filters.allows(&device));
+    }
+}
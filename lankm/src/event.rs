@@ -1,18 +1,17 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
 use bitflags::bitflags;
 
-#[derive(Copy, Clone, Debug)]
+use crate::hotkey::HotkeyAction;
+
+#[derive(Clone, Debug)]
 pub enum Event {
     Key(KeyEvent),
-    Hotkey,
-}
-
-impl Event {
-    pub fn to_bytes(self) -> [u8; 4] {
-        match self {
-            Event::Key(k) => k.to_bytes(),
-            _ => todo!(),
-        }
-    }
+    Pointer(PointerEvent),
+    Hotkey(HotkeyAction),
+    /// Text to type on the focused client, whatever its keyboard layout
+    Text(String),
 }
 
 #[repr(u8)]
@@ -20,49 +19,1125 @@ impl Event {
 pub enum KeyEventKind {
     Press = 0,
     Release = 1,
+    /// The key is still held, sent at the typematic rate
+    Repeat = 2,
 }
 
-impl From<u8> for KeyEventKind {
-    fn from(n: u8) -> Self {
+impl TryFrom<u8> for KeyEventKind {
+    type Error = DecodeError;
+
+    fn try_from(n: u8) -> Result<Self, Self::Error> {
         match n {
-            0 => KeyEventKind::Press,
-            1 => KeyEventKind::Release,
-            _ => panic!("Invalid KeyEventKind value"),
+            0 => Ok(KeyEventKind::Press),
+            1 => Ok(KeyEventKind::Release),
+            2 => Ok(KeyEventKind::Repeat),
+            _ => Err(DecodeError::UnknownKeyKind(n)),
         }
     }
 }
 
 bitflags! {
-    #[derive(Copy, Clone, Debug)]
-    pub struct Modifiers: u8 {
-        const CTRL  = 1 << 0;
-        const ALT   = 1 << 1;
-        const SHIFT = 1 << 2;
+    /// Held modifier keys and active locks. The first eight bits follow the
+    /// order of the HID modifier usages, 0xE0 to 0xE7.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub struct Modifiers: u16 {
+        const LCTRL       = 1 << 0;
+        const LSHIFT      = 1 << 1;
+        const LALT        = 1 << 2;
+        const LMETA       = 1 << 3;
+        const RCTRL       = 1 << 4;
+        const RSHIFT      = 1 << 5;
+        const RALT        = 1 << 6;
+        const RMETA       = 1 << 7;
+        const CAPS_LOCK   = 1 << 8;
+        const NUM_LOCK    = 1 << 9;
+        const SCROLL_LOCK = 1 << 10;
+
+        /// Either side
+        const CTRL  = Self::LCTRL.bits() | Self::RCTRL.bits();
+        const SHIFT = Self::LSHIFT.bits() | Self::RSHIFT.bits();
+        const ALT   = Self::LALT.bits() | Self::RALT.bits();
+        /// Super, the Windows key or Command
+        const META  = Self::LMETA.bits() | Self::RMETA.bits();
+        /// Right Alt is AltGr on most non-US layouts
+        const ALTGR = Self::RALT.bits();
+        const LOCKS = Self::CAPS_LOCK.bits() | Self::NUM_LOCK.bits() | Self::SCROLL_LOCK.bits();
+    }
+}
+
+pub const HID_CAPS_LOCK: u16 = 0x39;
+pub const HID_SCROLL_LOCK: u16 = 0x47;
+pub const HID_NUM_LOCK: u16 = 0x53;
+
+impl Modifiers {
+    /// The modifier a key is, if it's one
+    pub fn from_hid(hid: u16) -> Option<Self> {
+        (0xE0..=0xE7)
+            .contains(&hid)
+            .then(|| Self::from_bits_retain(1 << (hid - 0xE0)))
+    }
+
+    /// HID usages of the held modifier keys
+    pub fn held_keys(self) -> impl Iterator<Item = u16> {
+        (0xE0..=0xE7).filter(move |&hid| self.contains(Self::from_hid(hid).unwrap()))
+    }
+
+    /// HID usages of the keys toggling the active locks
+    pub fn lock_keys(self) -> impl Iterator<Item = u16> {
+        [
+            (Self::CAPS_LOCK, HID_CAPS_LOCK),
+            (Self::NUM_LOCK, HID_NUM_LOCK),
+            (Self::SCROLL_LOCK, HID_SCROLL_LOCK),
+        ]
+        .into_iter()
+        .filter(move |&(lock, _)| self.contains(lock))
+        .map(|(_, hid)| hid)
+    }
+}
+
+/// Follows modifier and lock keys through a stream of key events. Shared by
+/// all capture backends so hotkeys and remapping see the same state.
+pub struct ModifierTracker {
+    mods: Modifiers,
+}
+
+impl ModifierTracker {
+    /// `locks` is the lock state when capture starts, read from the
+    /// keyboard LEDs or the OS
+    pub fn new(locks: Modifiers) -> Self {
+        Self {
+            mods: locks & Modifiers::LOCKS,
+        }
+    }
+
+    pub fn update(&mut self, hid: u16, kind: KeyEventKind) -> Modifiers {
+        // The key is still held, nothing changes
+        if kind == KeyEventKind::Repeat {
+            return self.mods;
+        }
+
+        let pressed = kind == KeyEventKind::Press;
+        if let Some(modifier) = Modifiers::from_hid(hid) {
+            self.mods.set(modifier, pressed);
+        } else if pressed {
+            match hid {
+                HID_CAPS_LOCK => self.mods.toggle(Modifiers::CAPS_LOCK),
+                HID_NUM_LOCK => self.mods.toggle(Modifiers::NUM_LOCK),
+                HID_SCROLL_LOCK => self.mods.toggle(Modifiers::SCROLL_LOCK),
+                _ => {}
+            }
+        }
+
+        self.mods
+    }
+
+    pub fn mods(&self) -> Modifiers {
+        self.mods
+    }
+}
+
+/// The keys and buttons held on one machine, so exactly those can be
+/// released when it loses focus
+#[derive(Default)]
+pub struct PressedKeys {
+    keys: Vec<(UsagePage, u16)>,
+    buttons: Vec<PointerButton>,
+}
+
+impl PressedKeys {
+    /// Follows a key event, returns false for a release or repeat of a key
+    /// that isn't held here
+    pub fn key(&mut self, event: &KeyEvent) -> bool {
+        Self::update(&mut self.keys, (event.page, event.hid), event.kind)
+    }
+
+    /// Same as `key`, for pointer buttons
+    pub fn button(&mut self, button: PointerButton, kind: KeyEventKind) -> bool {
+        Self::update(&mut self.buttons, button, kind)
+    }
+
+    fn update<T: PartialEq>(held: &mut Vec<T>, item: T, kind: KeyEventKind) -> bool {
+        let was_held = held.contains(&item);
+        match kind {
+            KeyEventKind::Press => {
+                if !was_held {
+                    held.push(item);
+                }
+                true
+            }
+            KeyEventKind::Repeat => was_held,
+            KeyEventKind::Release => {
+                held.retain(|i| *i != item);
+                was_held
+            }
+        }
+    }
+
+    /// Held keys, in press order
+    pub fn keys(&self) -> &[(UsagePage, u16)] {
+        &self.keys
+    }
+
+    /// Makes `keys` the held keys, buttons stay as they are
+    pub fn set_keys(&mut self, keys: &[(UsagePage, u16)]) {
+        self.keys = keys.to_vec();
+    }
+
+    /// Release events for everything held, last pressed first
+    pub fn releases(&self) -> Vec<Event> {
+        let keys = self.keys.iter().rev().map(|&(page, hid)| {
+            Event::Key(KeyEvent {
+                page,
+                hid,
+                kind: KeyEventKind::Release,
+                mods: Modifiers::empty(),
+            })
+        });
+        let buttons = self.buttons.iter().rev().map(|&button| {
+            Event::Pointer(PointerEvent::Button {
+                button,
+                kind: KeyEventKind::Release,
+            })
+        });
+
+        keys.chain(buttons).collect()
+    }
+}
+
+/// HID usage page a key event's usage is from
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UsagePage {
+    /// Only its System Control usages, the power, sleep and wake buttons
+    GenericDesktop = 0x01,
+    Keyboard = 0x07,
+    /// Media, volume, brightness and application launch keys
+    Consumer = 0x0C,
+}
+
+impl TryFrom<u8> for UsagePage {
+    type Error = DecodeError;
+
+    fn try_from(n: u8) -> Result<Self, Self::Error> {
+        match n {
+            0x01 => Ok(UsagePage::GenericDesktop),
+            0x07 => Ok(UsagePage::Keyboard),
+            0x0C => Ok(UsagePage::Consumer),
+            _ => Err(DecodeError::UnknownUsagePage(n)),
+        }
     }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct KeyEvent {
+    pub page: UsagePage,
+    /// Usage within `page`
     pub hid: u16,
     pub kind: KeyEventKind,
     pub mods: Modifiers,
 }
 
 impl KeyEvent {
-    const SIZE: usize = std::mem::size_of::<Self>();
+    const SIZE: usize = 6;
 
     pub fn to_bytes(self) -> [u8; Self::SIZE] {
         let hid = self.hid.to_le_bytes();
         let kind = self.kind as u8;
+        let mods = self.mods.bits().to_le_bytes();
 
-        [hid[0], hid[1], kind, self.mods.bits()]
+        [hid[0], hid[1], kind, mods[0], mods[1], self.page as u8]
     }
 
-    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
-        let hid = u16::from_le_bytes([bytes[0], bytes[1]]);
-        let kind: KeyEventKind = bytes[2].into();
-        let mods = Modifiers::from_bits(bytes[3]).unwrap();
+    /// Whether this is the same key as `other`
+    pub fn same_key(&self, other: &KeyEvent) -> bool {
+        self.page == other.page && self.hid == other.hid
+    }
+}
+
+/// A keyboard key resolved to a keysym of the server's layout, for clients
+/// in keysym mode
+#[derive(Copy, Clone, Debug)]
+pub struct KeysymEvent {
+    pub keysym: u32,
+    /// The key itself, for clients whose layout has no such keysym
+    pub key: KeyEvent,
+}
+
+impl KeysymEvent {
+    const SIZE: usize = 4 + KeyEvent::SIZE;
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..4].copy_from_slice(&self.keysym.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.key.to_bytes());
+
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for KeysymEvent {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() != Self::SIZE {
+            return Err(DecodeError::Length("keysym event", bytes.len()));
+        }
+
+        Ok(Self {
+            keysym: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            key: bytes[4..].try_into()?,
+        })
+    }
+}
+
+impl TryFrom<&[u8]> for KeyEvent {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let &[hid0, hid1, kind, mods0, mods1, page] = bytes else {
+            return Err(DecodeError::Length("key event", bytes.len()));
+        };
+
+        let hid = u16::from_le_bytes([hid0, hid1]);
+        let kind = kind.try_into()?;
+        // Unknown modifiers come from newer peers, just ignore them
+        let mods = Modifiers::from_bits_truncate(u16::from_le_bytes([mods0, mods1]));
+        let page = page.try_into()?;
+
+        Ok(Self {
+            page,
+            hid,
+            kind,
+            mods,
+        })
+    }
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PointerButton {
+    Left = 0,
+    Right = 1,
+    Middle = 2,
+    Side = 3,
+    Extra = 4,
+    Forward = 5,
+    Back = 6,
+}
+
+impl TryFrom<u8> for PointerButton {
+    type Error = DecodeError;
+
+    fn try_from(n: u8) -> Result<Self, Self::Error> {
+        match n {
+            0 => Ok(PointerButton::Left),
+            1 => Ok(PointerButton::Right),
+            2 => Ok(PointerButton::Middle),
+            3 => Ok(PointerButton::Side),
+            4 => Ok(PointerButton::Extra),
+            5 => Ok(PointerButton::Forward),
+            6 => Ok(PointerButton::Back),
+            _ => Err(DecodeError::UnknownPointerButton(n)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum PointerEvent {
+    /// Relative motion, in device units
+    Motion { dx: i32, dy: i32 },
+    Button {
+        button: PointerButton,
+        kind: KeyEventKind,
+    },
+    /// Scroll, in wheel clicks. Positive `dy` scrolls up and positive `dx`
+    /// scrolls right, same as evdev
+    Wheel { dx: i32, dy: i32 },
+}
+
+impl PointerEvent {
+    const MOTION: u8 = 0;
+    const BUTTON: u8 = 1;
+    const WHEEL: u8 = 2;
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(9);
+        match self {
+            PointerEvent::Motion { dx, dy } => {
+                bytes.push(Self::MOTION);
+                bytes.extend_from_slice(&dx.to_le_bytes());
+                bytes.extend_from_slice(&dy.to_le_bytes());
+            }
+            PointerEvent::Button { button, kind } => {
+                bytes.push(Self::BUTTON);
+                bytes.push(button as u8);
+                bytes.push(kind as u8);
+            }
+            PointerEvent::Wheel { dx, dy } => {
+                bytes.push(Self::WHEEL);
+                bytes.extend_from_slice(&dx.to_le_bytes());
+                bytes.extend_from_slice(&dy.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for PointerEvent {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let read_i32 = |at: usize| {
+            bytes
+                .get(at..at + 4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(DecodeError::Length("pointer event", bytes.len()))
+        };
+
+        match bytes.first() {
+            Some(&Self::MOTION) => Ok(PointerEvent::Motion {
+                dx: read_i32(1)?,
+                dy: read_i32(5)?,
+            }),
+            Some(&Self::BUTTON) => {
+                let (button, kind) = match bytes.get(1..3) {
+                    Some(&[button, kind]) => (button, kind),
+                    _ => return Err(DecodeError::Length("pointer event", bytes.len())),
+                };
+
+                Ok(PointerEvent::Button {
+                    button: button.try_into()?,
+                    kind: kind.try_into()?,
+                })
+            }
+            Some(&Self::WHEEL) => Ok(PointerEvent::Wheel {
+                dx: read_i32(1)?,
+                dy: read_i32(5)?,
+            }),
+            Some(&kind) => Err(DecodeError::UnknownPointerEvent(kind)),
+            None => Err(DecodeError::Length("pointer event", 0)),
+        }
+    }
+}
+
+/// What the server's keyboard has held and toggled, sent to a client
+/// whenever it gets focus so it can match it
+#[derive(Clone, Debug)]
+pub struct InputState {
+    /// Held keys, in press order
+    pub keys: Vec<(UsagePage, u16)>,
+    /// Held modifiers and the lock state
+    pub mods: Modifiers,
+}
+
+impl InputState {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + 3 * self.keys.len());
+        bytes.extend_from_slice(&self.mods.bits().to_le_bytes());
+        for &(page, hid) in &self.keys {
+            bytes.push(page as u8);
+            bytes.extend_from_slice(&hid.to_le_bytes());
+        }
+
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for InputState {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        // The modifiers, then a page and a usage for each key
+        if bytes.len() < 2 || !(bytes.len() - 2).is_multiple_of(3) {
+            return Err(DecodeError::Length("input state", bytes.len()));
+        }
+
+        let keys = bytes[2..]
+            .chunks_exact(3)
+            .map(|key| Ok((key[0].try_into()?, u16::from_le_bytes([key[1], key[2]]))))
+            .collect::<Result<_, DecodeError>>()?;
+
+        Ok(Self {
+            keys,
+            mods: Modifiers::from_bits_truncate(u16::from_le_bytes([bytes[0], bytes[1]])),
+        })
+    }
+}
+
+// Wire protocol
+//
+// Every message is sent as a frame: a 1 byte message type, a 2 byte little
+// endian payload length and then the payload itself. The first frame in each
+// direction must be a Hello (client) or HelloAck (server), which carry the
+// magic, protocol version and capabilities of each side. Both sides then
+// prove they hold the key from pairing, see the pairing module.
+
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"LNKM";
+pub const PROTOCOL_VERSION: u16 = 10;
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct Capabilities: u32 {
+        const KEYBOARD = 1 << 0;
+        const POINTER  = 1 << 1;
+        /// The client wants the server's key repeats forwarded instead of
+        /// repeating keys itself
+        const KEY_REPEAT = 1 << 2;
+        const TEXT       = 1 << 3;
+        /// The client wants keyboard keys as keysyms of the server's
+        /// layout instead of key positions
+        const KEYSYMS    = 1 << 4;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Hello {
+    pub version: u16,
+    pub caps: Capabilities,
+    /// Name of the machine, clients are told apart by it
+    pub name: String,
+}
+
+impl Hello {
+    const FIXED_SIZE: usize = 10;
+
+    /// Hello describing this build of lankm
+    pub fn ours(name: &str) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            caps: Capabilities::all(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::FIXED_SIZE + self.name.len());
+        bytes.extend_from_slice(&PROTOCOL_MAGIC);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.caps.bits().to_le_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < Self::FIXED_SIZE || bytes[0..4] != PROTOCOL_MAGIC {
+            return Err(DecodeError::NotLankm);
+        }
+
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        // Unknown capabilities come from newer peers, just ignore them
+        let caps = Capabilities::from_bits_truncate(u32::from_le_bytes([
+            bytes[6], bytes[7], bytes[8], bytes[9],
+        ]));
+        let name = String::from_utf8(bytes[Self::FIXED_SIZE..].to_vec())
+            .map_err(|_| DecodeError::NotUtf8("peer name"))?;
+
+        Ok(Self {
+            version,
+            caps,
+            name,
+        })
+    }
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum MessageType {
+    Hello = 1,
+    HelloAck = 2,
+    Key = 3,
+    Pointer = 4,
+    Reject = 5,
+    Pake = 6,
+    Challenge = 7,
+    Proof = 8,
+    Ping = 9,
+    Pong = 10,
+    State = 11,
+    Leave = 12,
+    Text = 13,
+    Keysym = 14,
+}
+
+impl MessageType {
+    fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            1 => Some(MessageType::Hello),
+            2 => Some(MessageType::HelloAck),
+            3 => Some(MessageType::Key),
+            4 => Some(MessageType::Pointer),
+            5 => Some(MessageType::Reject),
+            6 => Some(MessageType::Pake),
+            7 => Some(MessageType::Challenge),
+            8 => Some(MessageType::Proof),
+            9 => Some(MessageType::Ping),
+            10 => Some(MessageType::Pong),
+            11 => Some(MessageType::State),
+            12 => Some(MessageType::Leave),
+            13 => Some(MessageType::Text),
+            14 => Some(MessageType::Keysym),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Message {
+    Hello(Hello),
+    HelloAck(Hello),
+    Key(KeyEvent),
+    Pointer(PointerEvent),
+    /// Sent by the server instead of a HelloAck when it refuses a client
+    Reject(String),
+    /// A side's public share of the pairing key exchange
+    Pake([u8; 32]),
+    /// Random nonce the peer must include in its Proof
+    Challenge([u8; 32]),
+    /// Proof of holding the shared key, over both challenges
+    Proof([u8; 32]),
+    /// Sent by the server every heartbeat interval, the client answers with
+    /// a Pong
+    Ping,
+    Pong,
+    /// What the server has held and toggled, sent when a client gets focus
+    /// or connects so it can match it
+    State(InputState),
+    /// Focus moved away from the client, it must let go of every key
+    Leave,
+    Text(String),
+    Keysym(KeysymEvent),
+}
+
+impl Message {
+    const HEADER_SIZE: usize = 3;
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (message_type, payload) = match self {
+            Message::Hello(hello) => (MessageType::Hello, hello.to_bytes()),
+            Message::HelloAck(hello) => (MessageType::HelloAck, hello.to_bytes()),
+            Message::Key(key) => (MessageType::Key, key.to_bytes().to_vec()),
+            Message::Pointer(pointer) => (MessageType::Pointer, pointer.to_bytes()),
+            Message::Reject(reason) => (MessageType::Reject, reason.as_bytes().to_vec()),
+            Message::Pake(bytes) => (MessageType::Pake, bytes.to_vec()),
+            Message::Challenge(bytes) => (MessageType::Challenge, bytes.to_vec()),
+            Message::Proof(bytes) => (MessageType::Proof, bytes.to_vec()),
+            Message::Ping => (MessageType::Ping, Vec::new()),
+            Message::Pong => (MessageType::Pong, Vec::new()),
+            Message::Leave => (MessageType::Leave, Vec::new()),
+            Message::Text(text) => (MessageType::Text, text.as_bytes().to_vec()),
+            Message::Keysym(keysym) => (MessageType::Keysym, keysym.to_bytes().to_vec()),
+            Message::State(state) => (MessageType::State, state.to_bytes()),
+        };
+
+        let len = u16::try_from(payload.len())
+            .map_err(|_| invalid_data("message payload too large"))?
+            .to_le_bytes();
+
+        // Build the whole frame first so it goes out in a single write
+        let mut frame = Vec::with_capacity(Self::HEADER_SIZE + payload.len());
+        frame.push(message_type as u8);
+        frame.extend_from_slice(&len);
+        frame.extend_from_slice(&payload);
+
+        writer.write_all(&frame)?;
+        writer.flush()
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut header = [0; Self::HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+
+        let len = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+
+        Ok(Self::decode(header[0], payload)?)
+    }
+
+    fn decode(message_type: u8, payload: Vec<u8>) -> Result<Self, DecodeError> {
+        let message_type = MessageType::from_u8(message_type)
+            .ok_or(DecodeError::UnknownMessageType(message_type))?;
+
+        match message_type {
+            MessageType::Hello => Ok(Message::Hello(Hello::from_bytes(&payload)?)),
+            MessageType::HelloAck => Ok(Message::HelloAck(Hello::from_bytes(&payload)?)),
+            MessageType::Key => Ok(Message::Key(payload.as_slice().try_into()?)),
+            MessageType::Pointer => Ok(Message::Pointer(payload.as_slice().try_into()?)),
+            MessageType::Reject => Ok(Message::Reject(
+                String::from_utf8_lossy(&payload).into_owned(),
+            )),
+            MessageType::Pake => Ok(Message::Pake(fixed_payload(payload, "pake")?)),
+            MessageType::Challenge => Ok(Message::Challenge(fixed_payload(payload, "challenge")?)),
+            MessageType::Proof => Ok(Message::Proof(fixed_payload(payload, "proof")?)),
+            MessageType::Ping => Ok(Message::Ping),
+            MessageType::Pong => Ok(Message::Pong),
+            MessageType::Leave => Ok(Message::Leave),
+            MessageType::Text => Ok(Message::Text(
+                String::from_utf8(payload).map_err(|_| DecodeError::NotUtf8("text"))?,
+            )),
+            MessageType::Keysym => Ok(Message::Keysym(payload.as_slice().try_into()?)),
+            MessageType::State => Ok(Message::State(payload.as_slice().try_into()?)),
+        }
+    }
+}
+
+/// Reads messages from a stream with a read timeout or in non-blocking
+/// mode, keeping partial frames around instead of losing them
+#[derive(Default)]
+pub struct MessageReader {
+    buffer: Vec<u8>,
+}
+
+impl MessageReader {
+    /// Returns None if no complete message arrived before the read timed
+    /// out or would have blocked
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<Option<Message>> {
+        loop {
+            if self.buffer.len() >= Message::HEADER_SIZE {
+                let len = u16::from_le_bytes([self.buffer[1], self.buffer[2]]) as usize;
+                let frame_size = Message::HEADER_SIZE + len;
+                if self.buffer.len() >= frame_size {
+                    let message = Message::read_from(&mut &self.buffer[..frame_size]);
+                    self.buffer.drain(..frame_size);
+                    return message.map(Some);
+                }
+            }
+
+            let mut chunk = [0; 4096];
+            match reader.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn fixed_payload<const N: usize>(
+    payload: Vec<u8>,
+    what: &'static str,
+) -> Result<[u8; N], DecodeError> {
+    let len = payload.len();
+    payload
+        .try_into()
+        .map_err(|_| DecodeError::Length(what, len))
+}
+
+/// Why a frame from a peer couldn't be decoded. The frame itself was read
+/// whole, so the stream can carry on after one.
+#[derive(Debug)]
+pub enum DecodeError {
+    UnknownMessageType(u8),
+    /// The payload of the named kind of message had the wrong size
+    Length(&'static str, usize),
+    UnknownKeyKind(u8),
+    UnknownUsagePage(u8),
+    UnknownPointerEvent(u8),
+    UnknownPointerButton(u8),
+    /// A Hello without the protocol magic
+    NotLankm,
+    NotUtf8(&'static str),
+}
+
+impl DecodeError {
+    /// The decode error behind an I/O error from reading messages, if that's
+    /// what it was
+    pub fn from_io(e: &io::Error) -> Option<&DecodeError> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownMessageType(n) => write!(f, "unknown message type {}", n),
+            DecodeError::Length(what, len) => {
+                write!(f, "wrong payload size {} for a {} message", len, what)
+            }
+            DecodeError::UnknownKeyKind(n) => write!(f, "unknown key event kind {}", n),
+            DecodeError::UnknownUsagePage(n) => write!(f, "unknown usage page {:#x}", n),
+            DecodeError::UnknownPointerEvent(n) => write!(f, "unknown pointer event {}", n),
+            DecodeError::UnknownPointerButton(n) => write!(f, "unknown pointer button {}", n),
+            DecodeError::NotLankm => write!(f, "peer is not speaking the lankm protocol"),
+            DecodeError::NotUtf8(what) => write!(f, "{} is not valid UTF-8", what),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(io::Error),
+    /// The peer speaks the given, incompatible, protocol version
+    VersionMismatch(u16),
+    /// The client was refused, with the reason the server gave
+    Rejected(String),
+    /// The peer couldn't prove it holds the key from pairing
+    AuthFailed,
+    /// We never paired with the named peer
+    NotPaired(String),
+    /// The named peer presented a different certificate than it used to
+    IdentityChanged(String),
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "{}", e),
+            HandshakeError::VersionMismatch(version) => write!(
+                f,
+                "peer speaks protocol version {} but we speak version {}",
+                version, PROTOCOL_VERSION
+            ),
+            HandshakeError::Rejected(reason) => write!(f, "{}", reason),
+            HandshakeError::AuthFailed => write!(f, "peer failed to authenticate"),
+            HandshakeError::NotPaired(name) => write!(f, "not paired with {}", name),
+            HandshakeError::IdentityChanged(name) => {
+                write!(f, "the identity of {} has changed", name)
+            }
+        }
+    }
+}
+
+/// Sends our Hello offering `caps` and waits for the server to acknowledge
+/// it
+pub fn client_handshake<S: Read + Write>(
+    stream: &mut S,
+    name: &str,
+    caps: Capabilities,
+) -> Result<Hello, HandshakeError> {
+    let mut hello = Hello::ours(name);
+    hello.caps = caps;
+    Message::Hello(hello).write_to(stream)?;
+
+    let ack = match Message::read_from(stream)? {
+        Message::HelloAck(ack) => ack,
+        Message::Reject(reason) => return Err(HandshakeError::Rejected(reason)),
+        m => return Err(invalid_data(&format!("expected HelloAck, got {:?}", m)).into()),
+    };
+
+    if ack.version != PROTOCOL_VERSION {
+        return Err(HandshakeError::VersionMismatch(ack.version));
+    }
+
+    Ok(ack)
+}
+
+/// Waits for a client's Hello and answers it. `accept` gets the final say
+/// on whether the client is let in, returning the reason when it isn't.
+pub fn server_handshake<S: Read + Write>(
+    stream: &mut S,
+    name: &str,
+    accept: impl FnOnce(&Hello) -> Result<(), String>,
+) -> Result<Hello, HandshakeError> {
+    let hello = match Message::read_from(stream)? {
+        Message::Hello(hello) => hello,
+        m => return Err(invalid_data(&format!("expected Hello, got {:?}", m)).into()),
+    };
+
+    // Always answer a version mismatch with our own version, so the client
+    // can report the problem on its side too
+    if hello.version != PROTOCOL_VERSION {
+        Message::HelloAck(Hello::ours(name)).write_to(stream)?;
+        return Err(HandshakeError::VersionMismatch(hello.version));
+    }
+
+    if let Err(reason) = accept(&hello) {
+        Message::Reject(reason.clone()).write_to(stream)?;
+        return Err(HandshakeError::Rejected(reason));
+    }
+
+    let mut ack = Hello::ours(name);
+    ack.caps &= hello.caps;
+    Message::HelloAck(ack).write_to(stream)?;
+
+    Ok(hello)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(message_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![message_type];
+        frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn decode(message_type: u8, payload: &[u8]) -> Result<Message, DecodeError> {
+        Message::decode(message_type, payload.to_vec())
+    }
+
+    fn key(kind: KeyEventKind) -> KeyEvent {
+        KeyEvent {
+            page: UsagePage::Keyboard,
+            hid: 0x04,
+            kind,
+            mods: Modifiers::LSHIFT | Modifiers::NUM_LOCK,
+        }
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let messages = [
+            Message::Hello(Hello::ours("laptop")),
+            Message::HelloAck(Hello {
+                version: 3,
+                caps: Capabilities::KEYBOARD | Capabilities::TEXT,
+                name: String::new(),
+            }),
+            Message::Key(key(KeyEventKind::Repeat)),
+            Message::Pointer(PointerEvent::Motion { dx: -5, dy: 70000 }),
+            Message::Pointer(PointerEvent::Button {
+                button: PointerButton::Back,
+                kind: KeyEventKind::Release,
+            }),
+            Message::Pointer(PointerEvent::Wheel { dx: 1, dy: -2 }),
+            Message::Reject("not paired".to_owned()),
+            Message::Pake([1; 32]),
+            Message::Challenge([2; 32]),
+            Message::Proof([3; 32]),
+            Message::Ping,
+            Message::Pong,
+            Message::State(InputState {
+                keys: vec![(UsagePage::Keyboard, 0xE1), (UsagePage::Consumer, 0xE9)],
+                mods: Modifiers::LSHIFT | Modifiers::CAPS_LOCK,
+            }),
+            Message::State(InputState {
+                keys: Vec::new(),
+                mods: Modifiers::empty(),
+            }),
+            Message::Leave,
+            Message::Text("grüße 👋".to_owned()),
+            Message::Keysym(KeysymEvent {
+                keysym: 0x10000e4,
+                key: KeyEvent {
+                    page: UsagePage::GenericDesktop,
+                    hid: 0x82,
+                    kind: KeyEventKind::Press,
+                    mods: Modifiers::empty(),
+                },
+            }),
+        ];
+
+        let mut types = Vec::new();
+        for message in &messages {
+            let mut bytes = Vec::new();
+            message.write_to(&mut bytes).unwrap();
+            types.push(bytes[0]);
+
+            let decoded = Message::read_from(&mut bytes.as_slice()).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+        }
+
+        types.sort();
+        types.dedup();
+        assert_eq!(types, (1..=14).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn rejects_unknown_message_types() {
+        for message_type in [0, 15, 0xFF] {
+            assert!(matches!(
+                decode(message_type, &[]),
+                Err(DecodeError::UnknownMessageType(n)) if n == message_type
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_lengths() {
+        let key = key(KeyEventKind::Press).to_bytes();
+        assert!(matches!(
+            decode(3, &key[..5]),
+            Err(DecodeError::Length("key event", 5))
+        ));
+        assert!(matches!(
+            decode(3, &[&key[..], &[0]].concat()),
+            Err(DecodeError::Length("key event", 7))
+        ));
+        assert!(matches!(
+            decode(14, &key),
+            Err(DecodeError::Length("keysym event", 6))
+        ));
+
+        for pointer in [&[0, 1, 0, 0, 0, 2, 0, 0][..], &[1, 0], &[2], &[]] {
+            assert!(matches!(
+                decode(4, pointer),
+                Err(DecodeError::Length("pointer event", _))
+            ));
+        }
+
+        for pake in [&[0; 31][..], &[0; 33], &[]] {
+            assert!(matches!(
+                decode(6, pake),
+                Err(DecodeError::Length("pake", _))
+            ));
+        }
+        assert!(matches!(
+            decode(7, &[0; 16]),
+            Err(DecodeError::Length("challenge", 16))
+        ));
+        assert!(matches!(
+            decode(8, &[0; 64]),
+            Err(DecodeError::Length("proof", 64))
+        ));
+
+        for state in [&[0][..], &[0, 0, 7], &[0, 0, 7, 4, 0, 7]] {
+            assert!(matches!(
+                decode(11, state),
+                Err(DecodeError::Length("input state", _))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_fields() {
+        let mut bytes = key(KeyEventKind::Press).to_bytes();
+        bytes[5] = 0x08;
+        assert!(matches!(
+            decode(3, &bytes),
+            Err(DecodeError::UnknownUsagePage(0x08))
+        ));
+
+        let mut bytes = key(KeyEventKind::Press).to_bytes();
+        bytes[2] = 3;
+        assert!(matches!(
+            decode(3, &bytes),
+            Err(DecodeError::UnknownKeyKind(3))
+        ));
+
+        assert!(matches!(
+            decode(11, &[0, 0, 0x07, 4, 0, 0x02, 4, 0]),
+            Err(DecodeError::UnknownUsagePage(0x02))
+        ));
+        assert!(matches!(
+            decode(4, &[1, 7, 0]),
+            Err(DecodeError::UnknownPointerButton(7))
+        ));
+        assert!(matches!(
+            decode(4, &[1, 0, 9]),
+            Err(DecodeError::UnknownKeyKind(9))
+        ));
+        assert!(matches!(
+            decode(4, &[3, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::UnknownPointerEvent(3))
+        ));
+
+        assert!(matches!(
+            decode(1, b"LNKX\x0a\0\0\0\0\0"),
+            Err(DecodeError::NotLankm)
+        ));
+        assert!(matches!(
+            decode(2, b"LNKM\x0a\0"),
+            Err(DecodeError::NotLankm)
+        ));
+        assert!(matches!(
+            decode(1, b"LNKM\x0a\0\0\0\0\0\xff"),
+            Err(DecodeError::NotUtf8("peer name"))
+        ));
+        assert!(matches!(
+            decode(13, b"\xc3"),
+            Err(DecodeError::NotUtf8("text"))
+        ));
+    }
+
+    #[test]
+    fn ignores_unknown_flags() {
+        let mut bytes = key(KeyEventKind::Press).to_bytes();
+        bytes[4] = 0xF0;
+        let Ok(Message::Key(event)) = decode(3, &bytes) else {
+            panic!("key event with unknown modifiers rejected");
+        };
+        assert_eq!(event.mods, Modifiers::LSHIFT);
+
+        let Ok(Message::Hello(hello)) = decode(1, b"LNKM\x0a\0\xff\xff\xff\xffpc") else {
+            panic!("hello with unknown capabilities rejected");
+        };
+        assert_eq!(hello.caps, Capabilities::all());
+        assert_eq!(hello.name, "pc");
+    }
+
+    #[test]
+    fn truncated_frames() {
+        let bytes = frame(3, &key(KeyEventKind::Press).to_bytes());
+        for len in 0..bytes.len() {
+            let e = Message::read_from(&mut &bytes[..len]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        }
+
+        // A bad frame read whole leaves the stream at the next one
+        let mut bytes = frame(0xFF, b"junk");
+        bytes.extend(frame(9, &[]));
+        let mut reader = MessageReader::default();
+        let mut stream = bytes.as_slice();
+        let e = reader.read_from(&mut stream).unwrap_err();
+        assert!(matches!(
+            DecodeError::from_io(&e),
+            Some(DecodeError::UnknownMessageType(0xFF))
+        ));
+        assert!(matches!(
+            reader.read_from(&mut stream),
+            Ok(Some(Message::Ping))
+        ));
+    }
+
+    #[test]
+    fn message_reader_keeps_partial_frames() {
+        struct Trickle<'a>(&'a [u8]);
+
+        // Hands out one byte per read, then times out like a socket would
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.split_first() {
+                    Some((&byte, rest)) => {
+                        buf[0] = byte;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    None => Err(io::ErrorKind::WouldBlock.into()),
+                }
+            }
+        }
+
+        let bytes = frame(13, b"hi");
+        let mut reader = MessageReader::default();
+        let mut stream = Trickle(&bytes[..2]);
+        assert!(matches!(reader.read_from(&mut stream), Ok(None)));
 
-        Self { hid, kind, mods }
+        let mut stream = Trickle(&bytes[2..]);
+        let Ok(Some(Message::Text(text))) = reader.read_from(&mut stream) else {
+            panic!("partial frame lost");
+        };
+        assert_eq!(text, "hi");
     }
 }
@@ -0,0 +1,431 @@
+use std::str::FromStr;
+
+use crate::event::{Event, KeyEvent, KeyEventKind, ModifierTracker, Modifiers, UsagePage};
+
+/// Used when no hotkeys are configured
+pub const DEFAULT_HOTKEY: &str = "ctrl+alt+tab=next";
+
+// Names accepted for keys in a hotkey, with their HID usage
+const KEY_NAMES: &[(&str, u16)] = &[
+    ("enter", 0x28),
+    ("escape", 0x29),
+    ("esc", 0x29),
+    ("backspace", 0x2A),
+    ("tab", 0x2B),
+    ("space", 0x2C),
+    ("minus", 0x2D),
+    ("equal", 0x2E),
+    ("leftbrace", 0x2F),
+    ("rightbrace", 0x30),
+    ("backslash", 0x31),
+    ("semicolon", 0x33),
+    ("apostrophe", 0x34),
+    ("grave", 0x35),
+    ("comma", 0x36),
+    ("dot", 0x37),
+    ("slash", 0x38),
+    ("capslock", 0x39),
+    ("printscreen", 0x46),
+    ("scrolllock", 0x47),
+    ("pause", 0x48),
+    ("insert", 0x49),
+    ("home", 0x4A),
+    ("pageup", 0x4B),
+    ("delete", 0x4C),
+    ("end", 0x4D),
+    ("pagedown", 0x4E),
+    ("right", 0x4F),
+    ("left", 0x50),
+    ("down", 0x51),
+    ("up", 0x52),
+];
+
+fn parse_key(name: &str) -> Option<u16> {
+    // Raw HID usages for anything without a name
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16).ok();
+    }
+
+    if let Some(n) = name.strip_prefix('f').and_then(|n| n.parse::<u16>().ok()) {
+        return (1..=12).contains(&n).then(|| 0x3A + n - 1);
+    }
+
+    match name.as_bytes() {
+        // Letters and digits, in HID order
+        [c @ b'a'..=b'z'] => Some(0x04 + (c - b'a') as u16),
+        [b'0'] => Some(0x27),
+        [c @ b'1'..=b'9'] => Some(0x1E + (c - b'1') as u16),
+        _ => KEY_NAMES
+            .iter()
+            .find(|(key_name, _)| *key_name == name)
+            .map(|(_, hid)| *hid),
+    }
+}
+
+// Names accepted for modifiers in a hotkey. The plain names accept either
+// side, the l/r prefixed ones only that side.
+const MODIFIER_NAMES: &[(&str, Modifiers)] = &[
+    ("ctrl", Modifiers::CTRL),
+    ("control", Modifiers::CTRL),
+    ("lctrl", Modifiers::LCTRL),
+    ("rctrl", Modifiers::RCTRL),
+    ("shift", Modifiers::SHIFT),
+    ("lshift", Modifiers::LSHIFT),
+    ("rshift", Modifiers::RSHIFT),
+    ("alt", Modifiers::ALT),
+    ("lalt", Modifiers::LALT),
+    ("ralt", Modifiers::RALT),
+    ("altgr", Modifiers::ALTGR),
+    ("super", Modifiers::META),
+    ("meta", Modifiers::META),
+    ("win", Modifiers::META),
+    ("lsuper", Modifiers::LMETA),
+    ("lmeta", Modifiers::LMETA),
+    ("lwin", Modifiers::LMETA),
+    ("rsuper", Modifiers::RMETA),
+    ("rmeta", Modifiers::RMETA),
+    ("rwin", Modifiers::RMETA),
+];
+
+/// A key together with the exact set of modifiers that must be held with it
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Hotkey {
+    pub mods: Modifiers,
+    pub hid: u16,
+}
+
+impl Hotkey {
+    /// Whether the held modifiers are exactly the ones this hotkey wants.
+    /// Locks don't matter, and a modifier given without a side is happy
+    /// with either side.
+    pub fn matches(&self, held: Modifiers) -> bool {
+        [
+            Modifiers::CTRL,
+            Modifiers::SHIFT,
+            Modifiers::ALT,
+            Modifiers::META,
+        ]
+        .into_iter()
+        .all(|group| {
+            let wanted = self.mods & group;
+            let held = held & group;
+            if wanted == group {
+                !held.is_empty()
+            } else {
+                held == wanted
+            }
+        })
+    }
+}
+
+impl FromStr for Hotkey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mods = Modifiers::empty();
+        let mut hid = None;
+
+        for part in s.to_lowercase().split('+') {
+            if let Some((_, modifier)) = MODIFIER_NAMES.iter().find(|(name, _)| *name == part) {
+                mods |= *modifier;
+                continue;
+            }
+
+            match part {
+                key if hid.is_none() => {
+                    hid = Some(parse_key(key).ok_or_else(|| format!("unknown key '{}'", key))?)
+                }
+                _ => return Err(format!("hotkey '{}' has more than one key", s)),
+            }
+        }
+
+        let hid = hid.ok_or_else(|| format!("hotkey '{}' has no key", s))?;
+        Ok(Self { mods, hid })
+    }
+}
+
+/// Targets meaning something other than a client, no client can be named
+/// like them
+pub const RESERVED_TARGETS: &[&str] = &["local", "next"];
+
+/// What the server does when a hotkey is pressed
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum HotkeyAction {
+    /// Give input back to the server
+    Local,
+    /// Send input to the named client
+    Client(String),
+    /// Cycle through the server and all connected clients
+    Next,
+}
+
+impl FromStr for HotkeyAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("missing hotkey target".to_owned()),
+            "local" => Ok(HotkeyAction::Local),
+            "next" => Ok(HotkeyAction::Next),
+            name => Ok(HotkeyAction::Client(name.to_owned())),
+        }
+    }
+}
+
+/// A `KEYS=TARGET` binding, e.g. `ctrl+alt+1=build-box`, `ctrl+alt+0=local`
+/// or `ctrl+alt+tab=next`
+#[derive(Clone, Debug)]
+pub struct HotkeyBinding {
+    pub hotkey: Hotkey,
+    pub action: HotkeyAction,
+}
+
+impl FromStr for HotkeyBinding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hotkey, action) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid hotkey '{}', expected KEYS=TARGET", s))?;
+
+        Ok(Self {
+            hotkey: hotkey.parse()?,
+            action: action.parse()?,
+        })
+    }
+}
+
+/// Tracks the held modifiers and turns key presses matching a binding into
+/// hotkey events. Shared by all capture backends so they agree on what a
+/// hotkey is.
+pub struct HotkeyMatcher {
+    bindings: Vec<HotkeyBinding>,
+    tracker: ModifierTracker,
+    /// Key that triggered the last hotkey, while it's still held
+    hotkey_key: Option<u16>,
+}
+
+impl HotkeyMatcher {
+    /// `locks` is the lock state when capture starts
+    pub fn new(bindings: Vec<HotkeyBinding>, locks: Modifiers) -> Self {
+        Self {
+            bindings,
+            tracker: ModifierTracker::new(locks),
+            hotkey_key: None,
+        }
+    }
+
+    /// Replaces the bindings, keeping track of what's held
+    pub fn set_bindings(&mut self, bindings: Vec<HotkeyBinding>) {
+        self.bindings = bindings;
+    }
+
+    /// Modifiers held right now, as of the last processed event
+    pub fn mods(&self) -> Modifiers {
+        self.tracker.mods()
+    }
+
+    /// Feeds a key event from a capture backend through the matcher,
+    /// returning the event to report for it. Repeats of a key that
+    /// triggered a hotkey are swallowed, they'd reach the new focus.
+    pub fn process(&mut self, page: UsagePage, hid: u16, kind: KeyEventKind) -> Option<Event> {
+        // Media and power keys are neither modifiers nor hotkeys
+        if page != UsagePage::Keyboard {
+            return Some(Event::Key(KeyEvent {
+                page,
+                hid,
+                kind,
+                mods: self.mods(),
+            }));
+        }
+
+        let mods = self.tracker.update(hid, kind);
+
+        if self.hotkey_key == Some(hid) {
+            match kind {
+                KeyEventKind::Repeat => return None,
+                KeyEventKind::Release => self.hotkey_key = None,
+                KeyEventKind::Press => {}
+            }
+        }
+
+        let binding = self
+            .bindings
+            .iter()
+            .find(|b| kind == KeyEventKind::Press && b.hotkey.hid == hid && b.hotkey.matches(mods));
+
+        match binding {
+            Some(binding) => {
+                self.hotkey_key = Some(hid);
+                Some(Event::Hotkey(binding.action.clone()))
+            }
+            None => Some(Event::Key(KeyEvent {
+                page,
+                hid,
+                kind,
+                mods,
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hotkey(s: &str) -> Hotkey {
+        s.parse().unwrap()
+    }
+
+    fn matcher(bindings: &[&str]) -> HotkeyMatcher {
+        let bindings = bindings.iter().map(|b| b.parse().unwrap()).collect();
+        HotkeyMatcher::new(bindings, Modifiers::empty())
+    }
+
+    fn press(matcher: &mut HotkeyMatcher, hid: u16) -> Option<Event> {
+        matcher.process(UsagePage::Keyboard, hid, KeyEventKind::Press)
+    }
+
+    fn is_key(event: Option<Event>) -> bool {
+        matches!(event, Some(Event::Key(_)))
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(
+            hotkey("Ctrl+Alt+Tab"),
+            Hotkey {
+                mods: Modifiers::CTRL | Modifiers::ALT,
+                hid: 0x2B
+            }
+        );
+        assert_eq!(hotkey("a").hid, 0x04);
+        assert_eq!(hotkey("z").hid, 0x1D);
+        assert_eq!(hotkey("1").hid, 0x1E);
+        assert_eq!(hotkey("0").hid, 0x27);
+        assert_eq!(hotkey("f1").hid, 0x3A);
+        assert_eq!(hotkey("f12").hid, 0x45);
+        assert_eq!(hotkey("f").hid, 0x09);
+        assert_eq!(hotkey("esc"), hotkey("escape"));
+        assert_eq!(hotkey("0x64").hid, 0x64);
+        assert_eq!(
+            hotkey("lctrl+rshift+altgr+win+up").mods,
+            Modifiers::LCTRL | Modifiers::RSHIFT | Modifiers::RALT | Modifiers::META
+        );
+
+        for invalid in [
+            "", "ctrl+alt", "ctrl+a+b", "f13", "f0", "0x", "0x10000", "hyper+a",
+        ] {
+            assert!(invalid.parse::<Hotkey>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_bindings() {
+        let binding: HotkeyBinding = "ctrl+alt+1=build-box".parse().unwrap();
+        assert_eq!(binding.hotkey, hotkey("ctrl+alt+1"));
+        assert_eq!(binding.action, HotkeyAction::Client("build-box".to_owned()));
+
+        let binding: HotkeyBinding = DEFAULT_HOTKEY.parse().unwrap();
+        assert_eq!(binding.action, HotkeyAction::Next);
+        let binding: HotkeyBinding = "ctrl+alt+0=local".parse().unwrap();
+        assert_eq!(binding.action, HotkeyAction::Local);
+
+        for invalid in ["ctrl+alt+1", "ctrl+alt+1=", "=local", "ctrl+nope=local"] {
+            assert!(invalid.parse::<HotkeyBinding>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn matches_exact_modifiers() {
+        let either = hotkey("ctrl+alt+tab");
+        assert!(either.matches(Modifiers::LCTRL | Modifiers::RALT));
+        assert!(either.matches(Modifiers::CTRL | Modifiers::LALT));
+        assert!(either.matches(Modifiers::LCTRL | Modifiers::LALT | Modifiers::CAPS_LOCK));
+        assert!(!either.matches(Modifiers::LCTRL));
+        assert!(!either.matches(Modifiers::LCTRL | Modifiers::LALT | Modifiers::LSHIFT));
+
+        let left = hotkey("lctrl+tab");
+        assert!(left.matches(Modifiers::LCTRL));
+        assert!(!left.matches(Modifiers::RCTRL));
+        assert!(!left.matches(Modifiers::CTRL));
+
+        assert!(hotkey("f5").matches(Modifiers::NUM_LOCK));
+        assert!(!hotkey("f5").matches(Modifiers::LMETA));
+    }
+
+    #[test]
+    fn turns_presses_into_hotkeys() {
+        let mut matcher = matcher(&["ctrl+alt+1=laptop", "ctrl+alt+tab=next"]);
+        assert!(is_key(press(&mut matcher, 0xE0)));
+        assert!(is_key(press(&mut matcher, 0xE2)));
+        assert_eq!(matcher.mods(), Modifiers::LCTRL | Modifiers::LALT);
+
+        assert!(matches!(
+            press(&mut matcher, 0x1E),
+            Some(Event::Hotkey(HotkeyAction::Client(name))) if name == "laptop"
+        ));
+        // Its repeats are swallowed, its release goes through
+        assert!(matcher
+            .process(UsagePage::Keyboard, 0x1E, KeyEventKind::Repeat)
+            .is_none());
+        assert!(is_key(matcher.process(
+            UsagePage::Keyboard,
+            0x1E,
+            KeyEventKind::Release
+        )));
+        assert!(matches!(
+            press(&mut matcher, 0x2B),
+            Some(Event::Hotkey(HotkeyAction::Next))
+        ));
+
+        // Not with another modifier held
+        assert!(is_key(press(&mut matcher, 0xE5)));
+        assert!(is_key(press(&mut matcher, 0x1E)));
+    }
+
+    #[test]
+    fn leaves_other_keys_alone() {
+        let mut matcher = matcher(&["ctrl+0x80=local"]);
+        press(&mut matcher, 0xE0);
+
+        // Consumer usages can share a number with a keyboard one
+        let Some(Event::Key(event)) =
+            matcher.process(UsagePage::Consumer, 0x80, KeyEventKind::Press)
+        else {
+            panic!("consumer key taken for a hotkey");
+        };
+        assert_eq!(event.mods, Modifiers::LCTRL);
+
+        // Only presses trigger
+        assert!(is_key(matcher.process(
+            UsagePage::Keyboard,
+            0x80,
+            KeyEventKind::Repeat
+        )));
+    }
+
+    #[test]
+    fn tracks_locks() {
+        let mut matcher = HotkeyMatcher::new(Vec::new(), Modifiers::NUM_LOCK | Modifiers::LCTRL);
+        assert_eq!(matcher.mods(), Modifiers::NUM_LOCK);
+
+        press(&mut matcher, 0x39);
+        matcher.process(UsagePage::Keyboard, 0x39, KeyEventKind::Release);
+        press(&mut matcher, 0x53);
+        assert_eq!(matcher.mods(), Modifiers::CAPS_LOCK);
+    }
+
+    #[test]
+    fn replaces_bindings() {
+        let mut matcher = matcher(&["ctrl+1=laptop"]);
+        press(&mut matcher, 0xE4);
+        matcher.set_bindings(vec!["rctrl+2=desktop".parse().unwrap()]);
+
+        assert!(is_key(press(&mut matcher, 0x1E)));
+        assert!(matches!(
+            press(&mut matcher, 0x1F),
+            Some(Event::Hotkey(HotkeyAction::Client(name))) if name == "desktop"
+        ));
+    }
+}
@@ -1,9 +1,26 @@
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
 use std::thread;
+use std::time::Duration;
 
-use evdev::{EventType, InputEventKind};
+use evdev::{EventType, InputEventKind, RelativeAxisType, Synchronization};
+use inotify::{Inotify, WatchMask};
 
-use crate::event::{Event, KeyEvent, KeyEventKind, Modifiers};
+use crate::config::KeyboardLayout;
+use crate::device_filter::{DeviceFilters, DeviceInfo};
+use crate::event::{
+    Event, KeyEvent, KeyEventKind, ModifierTracker, Modifiers, PointerButton, PointerEvent,
+    PressedKeys, UsagePage,
+};
+use crate::hotkey::{HotkeyBinding, HotkeyMatcher};
+use crate::input_injection::{
+    lock_state, usage_to_linux, CONSUMER_TO_LINUX, SYSTEM_CONTROL_TO_LINUX, VIRTUAL_DEVICE_NAME,
+};
+use crate::xkb::Keymap;
 
 const fn invert_linux_table(table: &[u8; 252]) -> [u8; 252] {
     let mut inverted = [0; 252];
@@ -24,129 +41,482 @@ const fn invert_linux_table(table: &[u8; 252]) -> [u8; 252] {
 const LINUX_TO_HID_TABLE: [u8; 252] =
     invert_linux_table(&crate::input_injection::HID_TO_LINUX_TABLE);
 
+fn linux_to_usage(key: evdev::Key) -> Option<(UsagePage, u16)> {
+    let find = |table: &[(u16, evdev::Key)]| {
+        table
+            .iter()
+            .find(|&&(_, k)| k == key)
+            .map(|&(usage, _)| usage)
+    };
+
+    // Media keys are in the keyboard table too, as the made up usages of
+    // boot protocol keyboards
+    if let Some(usage) = find(&CONSUMER_TO_LINUX) {
+        return Some((UsagePage::Consumer, usage));
+    }
+    if let Some(usage) = find(&SYSTEM_CONTROL_TO_LINUX) {
+        return Some((UsagePage::GenericDesktop, usage));
+    }
+
+    match LINUX_TO_HID_TABLE.get(key.0 as usize) {
+        Some(&hid) if hid != 0 => Some((UsagePage::Keyboard, hid as u16)),
+        _ => None,
+    }
+}
+
+fn linux_to_button(key: evdev::Key) -> Option<PointerButton> {
+    match key {
+        evdev::Key::BTN_LEFT => Some(PointerButton::Left),
+        evdev::Key::BTN_RIGHT => Some(PointerButton::Right),
+        evdev::Key::BTN_MIDDLE => Some(PointerButton::Middle),
+        evdev::Key::BTN_SIDE => Some(PointerButton::Side),
+        evdev::Key::BTN_EXTRA => Some(PointerButton::Extra),
+        evdev::Key::BTN_FORWARD => Some(PointerButton::Forward),
+        evdev::Key::BTN_BACK => Some(PointerButton::Back),
+        _ => None,
+    }
+}
+
+// What the device threads report, hotkeys are matched later on so that
+// modifiers held on one device apply to keys on another
+enum DeviceEvent {
+    Key {
+        page: UsagePage,
+        hid: u16,
+        kind: KeyEventKind,
+    },
+    Pointer(PointerEvent),
+    /// Not from a device, see `sync_locks`
+    SyncLocks(Modifiers),
+    /// Not from a device either, see `set_hotkeys`
+    Hotkeys(Vec<HotkeyBinding>),
+}
+
+// Lets `sync_locks` and `set_hotkeys` reach the injector thread
+static INJECTOR_SENDER: OnceLock<mpsc::Sender<DeviceEvent>> = OnceLock::new();
+
+// Layout `keysym` resolves keys with, set by `init`
+static KEYBOARD_LAYOUT: OnceLock<Option<KeyboardLayout>> = OnceLock::new();
+
+// The grabbed devices, set up by `init`
+static DEVICES: Mutex<Option<Devices>> = Mutex::new(None);
+
+/// How long a device thread waits for events before checking whether it
+/// should let go of its device
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where device nodes show up when plugged in
+const INPUT_DIR: &str = "/dev/input";
+
+/// How long to wait after a device node shows up before opening it, udev
+/// only sets its permissions afterwards
+const HOTPLUG_DELAY: Duration = Duration::from_millis(200);
+
+struct Devices {
+    filters: DeviceFilters,
+    /// By device path
+    grabbed: HashMap<PathBuf, GrabbedDevice>,
+    sender: mpsc::Sender<DeviceEvent>,
+}
+
+struct GrabbedDevice {
+    info: DeviceInfo,
+    /// Tells the device thread to let go of the device, also set by the
+    /// thread when the device is gone
+    stop: Arc<AtomicBool>,
+}
+
+impl Devices {
+    /// Forgets the devices that are gone, lets go of the ones the filters no
+    /// longer allow and grabs the keyboards and pointers they allow that
+    /// aren't grabbed yet. Returns the lock state of the first new keyboard.
+    fn scan(&mut self) -> Option<Modifiers> {
+        self.grabbed.retain(|_, device| {
+            if device.stop.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            let allowed = self.filters.allows(&device.info);
+            if !allowed {
+                log::info!("Letting go of {}, filtered out", device.info.name);
+                device.stop.store(true, Ordering::Relaxed);
+            }
+            allowed
+        });
+
+        log::debug!("Enumerating devices");
+        let mut locks = None;
+        for (path, mut device) in evdev::enumerate() {
+            if self.grabbed.contains_key(&path) {
+                continue;
+            }
+            let info = DeviceInfo {
+                name: device.name().unwrap_or("<no name>").to_owned(),
+                path,
+                phys: device.physical_path().map(str::to_owned),
+                vendor: device.input_id().vendor(),
+                product: device.input_id().product(),
+            };
+            let dev_name = info.name.clone();
+            log::debug!(
+                "Found device at {}: {} ({:04x}:{:04x}, {}) supporting events: {:?}",
+                info.path.display(),
+                dev_name,
+                info.vendor,
+                info.product,
+                info.phys.as_deref().unwrap_or("no phys"),
+                device.supported_events()
+            );
+
+            // Never our own injector, its events would loop back to us
+            if dev_name == VIRTUAL_DEVICE_NAME {
+                continue;
+            } else if !self.filters.allows(&info) {
+                log::debug!("Skipping {}, filtered out", dev_name);
+                continue;
+            } else if is_keyboard(&device) {
+                log::debug!("Using {} as keyboard", dev_name);
+                if locks.is_none() {
+                    locks = lock_state(&device);
+                }
+            } else if is_pointer(&device) {
+                log::debug!("Using {} as pointer", dev_name);
+            } else {
+                continue;
+            }
+
+            if let Err(e) = device.grab() {
+                log::warn!("Could not grab {}: {}", dev_name, e);
+                continue;
+            }
+
+            log::debug!("Starting thread for device: {}", dev_name);
+            let stop = Arc::new(AtomicBool::new(false));
+            let args = DeviceThreadArgs {
+                device,
+                sender: self.sender.clone(),
+                stop: stop.clone(),
+            };
+            thread::spawn(move || device_thread(args));
+            self.grabbed
+                .insert(info.path.clone(), GrabbedDevice { info, stop });
+        }
+        log::debug!("Done enumerating devices");
+
+        locks
+    }
+}
+
 struct DeviceThreadArgs {
-    pub kbd: evdev::Device,
-    pub sender: mpsc::Sender<Event>,
+    pub device: evdev::Device,
+    pub sender: mpsc::Sender<DeviceEvent>,
+    pub stop: Arc<AtomicBool>,
+}
+
+/// Waits up to `timeout` for the device to have events, returns whether it
+/// has any
+fn wait_for_events(device: &evdev::Device, timeout: Duration) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd: device.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) };
+    if ready < 0 {
+        let e = io::Error::last_os_error();
+        return match e.kind() {
+            io::ErrorKind::Interrupted => Ok(false),
+            _ => Err(e),
+        };
+    }
+
+    Ok(ready > 0)
 }
 
 fn device_thread(mut args: DeviceThreadArgs) {
-    let mut mods = Modifiers::empty();
-    let dev_name = args.kbd.name().unwrap_or("<no name>").to_owned();
+    let dev_name = args.device.name().unwrap_or("<no name>").to_owned();
 
-    loop {
-        let events = args.kbd.fetch_events().unwrap();
+    // Relative axes are reported one at a time, accumulate them until the
+    // SYN_REPORT so a diagonal move is forwarded as a single event
+    let mut motion = (0, 0);
+    let mut wheel = (0, 0);
+    // Released when the device is let go of
+    let mut pressed = PressedKeys::default();
+
+    while !args.stop.load(Ordering::Relaxed) {
+        match wait_for_events(&args.device, POLL_INTERVAL) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                log::error!("Could not wait for events from {}: {}", dev_name, e);
+                break;
+            }
+        }
+
+        let events = match args.device.fetch_events() {
+            Ok(events) => events,
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                log::info!("Device {} was unplugged", dev_name);
+                break;
+            }
+            Err(e) => {
+                log::error!("Could not read events from {}: {}", dev_name, e);
+                break;
+            }
+        };
         for event in events {
-            if let InputEventKind::Key(key) = event.kind() {
-                // Ignore key repeats
-                if event.value() == 2 {
-                    continue;
-                }
+            match event.kind() {
+                InputEventKind::Key(key) => {
+                    let kind = match event.value() {
+                        0 => KeyEventKind::Release,
+                        1 => KeyEventKind::Press,
+                        2 => KeyEventKind::Repeat,
+                        value => {
+                            log::error!("Unknown event value: {}", value);
+                            continue;
+                        }
+                    };
 
-                let hid = match LINUX_TO_HID_TABLE.get(key.0 as usize) {
-                    Some(hid) => *hid as u16,
-                    None => {
-                        log::warn!("Unknown HID {} from device {}", key.0, dev_name);
+                    if let Some(button) = linux_to_button(key) {
+                        if kind == KeyEventKind::Repeat {
+                            continue;
+                        }
+                        pressed.button(button, kind);
+                        args.sender
+                            .send(DeviceEvent::Pointer(PointerEvent::Button { button, kind }))
+                            .unwrap();
                         continue;
                     }
-                };
-                let kind = match event.value() {
-                    0 => KeyEventKind::Release,
-                    1 => KeyEventKind::Press,
-                    value => {
-                        log::error!("Unknown event value: {}", value);
+
+                    let Some((page, hid)) = linux_to_usage(key) else {
+                        log::warn!("Unknown key {} from device {}", key.0, dev_name);
                         continue;
-                    }
-                };
+                    };
+                    pressed.key(&KeyEvent {
+                        page,
+                        hid,
+                        kind,
+                        mods: Modifiers::empty(),
+                    });
 
-                match hid {
-                    0xE0 | 0xE4 => mods.set(Modifiers::CTRL, kind == KeyEventKind::Press),
-                    0xE1 | 0xE5 => mods.set(Modifiers::SHIFT, kind == KeyEventKind::Press),
-                    0xE2 | 0xE6 => mods.set(Modifiers::ALT, kind == KeyEventKind::Press),
+                    args.sender
+                        .send(DeviceEvent::Key { page, hid, kind })
+                        .unwrap();
+                }
+                InputEventKind::RelAxis(axis) => match axis {
+                    RelativeAxisType::REL_X => motion.0 += event.value(),
+                    RelativeAxisType::REL_Y => motion.1 += event.value(),
+                    RelativeAxisType::REL_HWHEEL => wheel.0 += event.value(),
+                    RelativeAxisType::REL_WHEEL => wheel.1 += event.value(),
                     _ => {}
+                },
+                InputEventKind::Synchronization(Synchronization::SYN_REPORT) => {
+                    if motion != (0, 0) {
+                        let (dx, dy) = std::mem::take(&mut motion);
+                        args.sender
+                            .send(DeviceEvent::Pointer(PointerEvent::Motion { dx, dy }))
+                            .unwrap();
+                    }
+                    if wheel != (0, 0) {
+                        let (dx, dy) = std::mem::take(&mut wheel);
+                        args.sender
+                            .send(DeviceEvent::Pointer(PointerEvent::Wheel { dx, dy }))
+                            .unwrap();
+                    }
                 }
+                _ => {}
+            }
+        }
+    }
 
-                let event = match hid {
-                    0x2B if kind == KeyEventKind::Press
-                        && mods.contains(Modifiers::CTRL | Modifiers::ALT) =>
-                    {
-                        Event::Hotkey
-                    }
-                    _ => Event::Key(KeyEvent { hid, kind, mods }),
-                };
+    for release in pressed.releases() {
+        let event = match release {
+            Event::Key(k) => DeviceEvent::Key {
+                page: k.page,
+                hid: k.hid,
+                kind: k.kind,
+            },
+            Event::Pointer(p) => DeviceEvent::Pointer(p),
+            _ => continue,
+        };
+        let _ = args.sender.send(event);
+    }
+    let _ = args.device.ungrab();
+    args.stop.store(true, Ordering::Relaxed);
+    log::debug!("Stopped thread for device: {}", dev_name);
+}
+
+fn is_keyboard(device: &evdev::Device) -> bool {
+    device.supported_events().contains(EventType::KEY)
+        && device.supported_events().contains(EventType::REPEAT)
+}
+
+fn is_pointer(device: &evdev::Device) -> bool {
+    let has_motion = device.supported_relative_axes().is_some_and(|axes| {
+        axes.contains(RelativeAxisType::REL_X) && axes.contains(RelativeAxisType::REL_Y)
+    });
+    let has_buttons = device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(evdev::Key::BTN_LEFT));
+
+    has_motion && has_buttons
+}
+
+/// Makes the lock state of this machine match `locks`, for when it gets
+/// focus back after the locks were toggled on a client
+pub fn sync_locks(locks: Modifiers) {
+    if let Some(sender) = INJECTOR_SENDER.get() {
+        let _ = sender.send(DeviceEvent::SyncLocks(locks));
+    }
+}
+
+/// Grabs the devices plugged in after capture started, and forgets the ones
+/// unplugged
+fn watch_devices() {
+    let watched = Inotify::init().and_then(|inotify| {
+        inotify.watches().add(
+            INPUT_DIR,
+            WatchMask::CREATE | WatchMask::ATTRIB | WatchMask::DELETE,
+        )?;
+        Ok(inotify)
+    });
+    let mut inotify = match watched {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            log::warn!("Can't watch for new input devices: {}", e);
+            return;
+        }
+    };
 
-                args.sender.send(event).unwrap();
+    let mut buffer = [0; 4096];
+    loop {
+        let mut events = match inotify.read_events_blocking(&mut buffer) {
+            Ok(events) => events,
+            Err(e) => {
+                log::error!("Stopped watching for new input devices: {}", e);
+                return;
             }
+        };
+        let is_device = |name: Option<&std::ffi::OsStr>| {
+            name.is_some_and(|name| name.to_string_lossy().starts_with("event"))
+        };
+        if !events.any(|event| is_device(event.name)) {
+            continue;
+        }
+
+        thread::sleep(HOTPLUG_DELAY);
+        if let Some(devices) = DEVICES.lock().unwrap().as_mut() {
+            devices.scan();
         }
     }
 }
 
-pub fn init<F: 'static + Send + FnMut(Event) -> bool>(mut callback: F) {
-    log::debug!("Enumerating devices");
-    let mut keyboards = Vec::new();
-    for (path, device) in evdev::enumerate() {
-        let dev_name = device.name().unwrap_or("<no name>");
-        log::debug!(
-            "Found device at {}: {} supporting events: {:?}",
-            path.display(),
-            dev_name,
-            device.supported_events()
-        );
-
-        if device.supported_events().contains(EventType::KEY)
-            && device.supported_events().contains(EventType::REPEAT)
-        {
-            log::debug!("Using {} as keyboard", dev_name);
-            keyboards.push(device);
-        }
+/// Replaces the focus switching hotkeys
+pub fn set_hotkeys(hotkeys: Vec<HotkeyBinding>) {
+    if let Some(sender) = INJECTOR_SENDER.get() {
+        let _ = sender.send(DeviceEvent::Hotkeys(hotkeys));
+    }
+}
+
+/// Replaces the device filters, grabbing and letting go of devices to match
+pub fn set_device_filters(filters: DeviceFilters) {
+    if let Some(devices) = DEVICES.lock().unwrap().as_mut() {
+        devices.filters = filters;
+        devices.scan();
     }
-    log::debug!("Done enumerating devices");
+}
+
+/// The keysym a key types in the layout of this machine, for clients in
+/// keysym mode. Locks are left out, the client applies its own.
+pub fn keysym(event: &KeyEvent) -> Option<u32> {
+    static KEYMAP: OnceLock<Option<Keymap>> = OnceLock::new();
+
+    let layout = KEYBOARD_LAYOUT.get().and_then(Option::as_ref);
+    let keymap = KEYMAP.get_or_init(|| match Keymap::load(layout) {
+        Ok(keymap) => Some(keymap),
+        Err(e) => {
+            log::error!("Can't resolve keysyms, sending key positions: {}", e);
+            None
+        }
+    });
+    let key = usage_to_linux(event.page, event.hid)?;
+
+    keymap.as_ref()?.keysym(
+        key.code(),
+        event.mods.intersects(Modifiers::SHIFT),
+        event.mods.contains(Modifiers::ALTGR),
+    )
+}
+
+/// Returns the lock state capture started with
+pub fn init<F: 'static + Send + FnMut(Event) -> bool>(
+    hotkeys: Vec<HotkeyBinding>,
+    filters: &DeviceFilters,
+    keyboard_layout: Option<KeyboardLayout>,
+    mut callback: F,
+) -> Modifiers {
+    let _ = KEYBOARD_LAYOUT.set(keyboard_layout.clone());
 
     // Because we can't stop keyboard events from being propagated like
     // in the windows implementation we do a little dance here: Grab all
     // devices we can, and when we want them to propagate funnel all events
     // into an injector
-    let (inj_sender, inj_receiver) = mpsc::channel::<Event>();
+    let (inj_sender, inj_receiver) = mpsc::channel::<DeviceEvent>();
+    let _ = INJECTOR_SENDER.set(inj_sender.clone());
+
+    let mut devices = Devices {
+        filters: filters.clone(),
+        grabbed: HashMap::new(),
+        sender: inj_sender,
+    };
+    let locks = devices.scan().unwrap_or(Modifiers::empty());
+    *DEVICES.lock().unwrap() = Some(devices);
+    thread::spawn(watch_devices);
+
     thread::spawn(move || {
-        let mut injector = crate::input_injection::InputInjector::new();
+        let mut injector = crate::input_injection::InputInjector::new(keyboard_layout);
+        let mut matcher = HotkeyMatcher::new(hotkeys, locks);
+        // Only what went through the injector, the locks toggled while a
+        // client had focus never reached this machine
+        let mut local = ModifierTracker::new(locks);
 
         loop {
-            let event = inj_receiver.recv().unwrap();
-            let blocked = callback(event);
-            match event {
-                Event::Key(k) if !blocked => {
-                    injector.emit(k);
+            let event = match inj_receiver.recv().unwrap() {
+                DeviceEvent::Key { page, hid, kind } => match matcher.process(page, hid, kind) {
+                    Some(event) => event,
+                    None => continue,
+                },
+                DeviceEvent::Pointer(p) => Event::Pointer(p),
+                DeviceEvent::SyncLocks(locks) => {
+                    injector.toggle_locks((local.mods() ^ locks) & Modifiers::LOCKS);
+                    local = ModifierTracker::new(locks);
+                    continue;
                 }
-                Event::Hotkey if blocked => {
-                    let mut release = |hid| {
-                        injector.emit(KeyEvent {
-                            hid,
-                            kind: KeyEventKind::Release,
-                            mods: Modifiers::empty(),
-                        })
-                    };
+                DeviceEvent::Hotkeys(hotkeys) => {
+                    matcher.set_bindings(hotkeys);
+                    continue;
+                }
+            };
+            if callback(event.clone()) {
+                // Focus is elsewhere, let go of whatever went through before
+                // it moved, like a hotkey's modifiers
+                injector.release_all();
+                local = ModifierTracker::new(local.mods() & Modifiers::LOCKS);
+                continue;
+            }
 
-                    release(0xE0);
-                    release(0xE4);
-                    release(0xE2);
-                    release(0xE6);
+            match event {
+                Event::Key(k) => {
+                    if k.page == UsagePage::Keyboard {
+                        local.update(k.hid, k.kind);
+                    }
+                    injector.emit(k);
                 }
+                Event::Pointer(p) => injector.emit_pointer(p),
                 _ => {}
             }
         }
     });
 
-    for mut kbd in keyboards.into_iter() {
-        log::debug!(
-            "Starting thread for device: {}",
-            kbd.name().unwrap_or("<no name>")
-        );
-
-        kbd.grab().unwrap();
-
-        let args = DeviceThreadArgs {
-            kbd,
-            sender: inj_sender.clone(),
-        };
-        thread::spawn(move || device_thread(args));
-    }
+    locks
 }
@@ -1,13 +1,25 @@
+use std::sync::Mutex;
 use std::thread;
 
-use crate::event::{Event, KeyEvent, KeyEventKind, Modifiers};
-use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, WPARAM};
+use crate::config::KeyboardLayout;
+use crate::device_filter::DeviceFilters;
+use crate::event::{
+    Event, KeyEvent, KeyEventKind, Modifiers, PointerButton, PointerEvent, UsagePage,
+};
+use crate::hotkey::{HotkeyBinding, HotkeyMatcher};
+use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, POINT, WPARAM};
 use windows::Win32::UI::Input::KeyboardAndMouse::{
-    SendInput, INPUT, INPUT_0, INPUT_TYPE, KEYBDINPUT, KEYBD_EVENT_FLAGS, VK_LCONTROL, VK_LMENU,
+    GetKeyState, SendInput, INPUT, INPUT_0, INPUT_TYPE, KEYBDINPUT, KEYBD_EVENT_FLAGS,
+    KEYEVENTF_EXTENDEDKEY, KEYEVENTF_KEYUP, MOUSEEVENTF_LEFTUP, MOUSEEVENTF_MIDDLEUP,
+    MOUSEEVENTF_RIGHTUP, MOUSEEVENTF_XUP, MOUSEINPUT, VIRTUAL_KEY, VK_CAPITAL, VK_NUMLOCK,
+    VK_SCROLL,
 };
 use windows::Win32::UI::WindowsAndMessaging::{
     CallNextHookEx, DispatchMessageW, GetMessageW, SetWindowsHookExW, TranslateMessage, HHOOK,
-    KBDLLHOOKSTRUCT, MSG, WH_KEYBOARD_LL, WM_KEYDOWN, WM_KEYUP, WM_SYSKEYDOWN, WM_SYSKEYUP,
+    KBDLLHOOKSTRUCT, LLMHF_INJECTED, MSG, MSLLHOOKSTRUCT, WHEEL_DELTA, WH_KEYBOARD_LL, WH_MOUSE_LL,
+    WM_KEYDOWN, WM_KEYUP, WM_LBUTTONDOWN, WM_LBUTTONUP, WM_MBUTTONDOWN, WM_MBUTTONUP,
+    WM_MOUSEHWHEEL, WM_MOUSEMOVE, WM_MOUSEWHEEL, WM_RBUTTONDOWN, WM_RBUTTONUP, WM_SYSKEYDOWN,
+    WM_SYSKEYUP, WM_XBUTTONDOWN, WM_XBUTTONUP, XBUTTON1, XBUTTON2,
 };
 
 // From https://learn.microsoft.com/en-us/windows/win32/inputdev/about-keyboard-input#scan-codes
@@ -25,22 +37,67 @@ static SCANCODE_TABLE: [u16; 256] = [
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
 ];
 static EXTENDED_TABLE: [u16; 256] = [
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 182, 0, 0, 0, 0, 0, 0, 0, 0, 181, 0, 0, 88,
-    228, 0, 0, 226, 402, 205, 0, 183, 0, 0, 0, 0, 0, 0, 0, 0, 0, 234, 0, 233, 0, 547, 0, 0, 84, 0,
-    70, 230, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 72, 0, 74, 82, 75, 0, 80, 0, 79, 0, 77, 81, 78,
-    73, 76, 0, 0, 0, 0, 0, 0, 0, 227, 231, 101, 102, 130, 0, 0, 0, 131, 0, 545, 554, 551, 550, 549,
-    548, 404, 394, 387, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 88, 228, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 84, 0, 70, 230, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 72, 0, 74, 82, 75, 0, 80, 0, 79, 0, 77, 81, 78, 73, 76, 0, 0, 0, 0, 0,
+    0, 0, 227, 231, 101, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+// Extended scan codes of the keys outside the keyboard usage page
+static EXTENDED_USAGES: [(u32, UsagePage, u16); 21] = [
+    (0x10, UsagePage::Consumer, 0xB6),
+    (0x19, UsagePage::Consumer, 0xB5),
+    (0x20, UsagePage::Consumer, 0xE2),
+    (0x21, UsagePage::Consumer, 0x192),
+    (0x22, UsagePage::Consumer, 0xCD),
+    (0x24, UsagePage::Consumer, 0xB7),
+    (0x2E, UsagePage::Consumer, 0xEA),
+    (0x30, UsagePage::Consumer, 0xE9),
+    (0x32, UsagePage::Consumer, 0x223),
+    (0x5E, UsagePage::GenericDesktop, 0x81),
+    (0x5F, UsagePage::GenericDesktop, 0x82),
+    (0x63, UsagePage::GenericDesktop, 0x83),
+    (0x65, UsagePage::Consumer, 0x221),
+    (0x66, UsagePage::Consumer, 0x22A),
+    (0x67, UsagePage::Consumer, 0x227),
+    (0x68, UsagePage::Consumer, 0x226),
+    (0x69, UsagePage::Consumer, 0x225),
+    (0x6A, UsagePage::Consumer, 0x224),
+    (0x6B, UsagePage::Consumer, 0x194),
+    (0x6C, UsagePage::Consumer, 0x18A),
+    (0x6D, UsagePage::Consumer, 0x183),
 ];
 
+// AltGr comes with a fake left Ctrl press, marked with this bit in its scan code
+const ALTGR_FAKE_CTRL: u32 = 0x200;
+
 type CallbackFunction = Box<dyn FnMut(Event) -> bool + Send>;
 
 static mut GLOBAL_CALLBACK: Option<CallbackFunction> = None;
 
-static mut GLOBAL_MODS: Modifiers = Modifiers::empty();
+static mut GLOBAL_MATCHER: Option<HotkeyMatcher> = None;
+
+// Bindings from `set_hotkeys`, picked up by the hook on the next key
+static NEW_HOTKEYS: Mutex<Option<Vec<HotkeyBinding>>> = Mutex::new(None);
+
+// Windows reports key repeats as more key downs, a key down for the key
+// that's already down is a repeat
+static mut LAST_KEY_DOWN: Option<(UsagePage, u16)> = None;
+
+// Keys (virtual key, scan code, extended) and buttons we let through and
+// haven't seen released, so exactly those can be released when focus moves
+// away
+static mut PASSED_KEYS: Vec<(VIRTUAL_KEY, u16, bool)> = Vec::new();
+static mut PASSED_BUTTONS: Vec<PointerButton> = Vec::new();
+
+// Cursor position of the last mouse move we let through, used to turn the
+// absolute positions the hook gets into relative motion
+static mut LAST_MOUSE_POS: Option<POINT> = None;
 
 unsafe extern "system" fn keyboard_hook(code: i32, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
     let kbd_event: KBDLLHOOKSTRUCT = *(l_param.0 as *const _);
@@ -50,90 +107,287 @@ unsafe extern "system" fn keyboard_hook(code: i32, w_param: WPARAM, l_param: LPA
         return CallNextHookEx(HHOOK(std::ptr::null_mut()), code, w_param, l_param);
     }
 
-    let hid = if kbd_event.flags.0 & 1 == 1 {
-        EXTENDED_TABLE[kbd_event.scanCode as usize]
+    if kbd_event.scanCode & ALTGR_FAKE_CTRL != 0 {
+        // Windows needs it to make AltGr work locally, but a client gets the
+        // right Alt itself and handles AltGr its own way
+        return CallNextHookEx(HHOOK(std::ptr::null_mut()), code, w_param, l_param);
+    }
+
+    let extended = kbd_event.flags.0 & 1 == 1;
+    let table = if extended {
+        &EXTENDED_TABLE
     } else {
-        SCANCODE_TABLE[kbd_event.scanCode as usize]
+        &SCANCODE_TABLE
+    };
+    let usage = EXTENDED_USAGES
+        .iter()
+        .find(|&&(scan, _, _)| extended && scan == kbd_event.scanCode)
+        .map(|&(_, page, hid)| (page, hid))
+        .or_else(|| match table.get(kbd_event.scanCode as usize) {
+            Some(&hid) if hid != 0 => Some((UsagePage::Keyboard, hid)),
+            _ => None,
+        });
+    let Some(usage @ (page, hid)) = usage else {
+        // Nothing to forward it as, let it through
+        return CallNextHookEx(HHOOK(std::ptr::null_mut()), code, w_param, l_param);
     };
 
     let kind = match w_param.0 as u32 {
-        WM_KEYDOWN | WM_SYSKEYDOWN => KeyEventKind::Press,
-        WM_KEYUP | WM_SYSKEYUP => KeyEventKind::Release,
+        WM_KEYDOWN | WM_SYSKEYDOWN if LAST_KEY_DOWN == Some(usage) => KeyEventKind::Repeat,
+        WM_KEYDOWN | WM_SYSKEYDOWN => {
+            LAST_KEY_DOWN = Some(usage);
+            KeyEventKind::Press
+        }
+        WM_KEYUP | WM_SYSKEYUP => {
+            if LAST_KEY_DOWN == Some(usage) {
+                LAST_KEY_DOWN = None;
+            }
+            KeyEventKind::Release
+        }
         _ => panic!("Invalid wParam"),
     };
 
-    // TODO: Copied from Linux implementation, extract into a method
-    match hid {
-        0xE0 | 0xE4 => GLOBAL_MODS.set(Modifiers::CTRL, kind == KeyEventKind::Press),
-        0xE1 | 0xE5 => GLOBAL_MODS.set(Modifiers::SHIFT, kind == KeyEventKind::Press),
-        0xE2 | 0xE6 => GLOBAL_MODS.set(Modifiers::ALT, kind == KeyEventKind::Press),
-        _ => {}
+    let matcher = GLOBAL_MATCHER.as_mut().unwrap();
+    if let Some(hotkeys) = NEW_HOTKEYS.lock().unwrap().take() {
+        matcher.set_bindings(hotkeys);
     }
-
-    let event = match hid {
-        0x2B if kind == KeyEventKind::Press
-            && GLOBAL_MODS.contains(Modifiers::CTRL | Modifiers::ALT) =>
-        {
-            Event::Hotkey
-        }
-        _ => Event::Key(KeyEvent {
-            hid,
-            kind,
-            mods: Modifiers::empty(),
-        }),
+    let Some(event) = matcher.process(page, hid, kind) else {
+        // Repeat of a hotkey, its press was swallowed too
+        return LRESULT(1);
     };
 
     let cb = GLOBAL_CALLBACK.as_mut().unwrap();
 
-    let handled = cb(event);
+    if cb(event) {
+        // Focus is elsewhere, let go of whatever went through before it
+        // moved, like a hotkey's modifiers
+        release_passed();
+        return LRESULT(1);
+    }
 
-    match (event, handled) {
-        (Event::Key(_), true) => LRESULT(1),
-        (Event::Key(_), false) | (Event::Hotkey, false) => {
-            CallNextHookEx(HHOOK(std::ptr::null_mut()), code, w_param, l_param)
-        }
-        (Event::Hotkey, true) => {
-            // Release CTRL and ALT
-            let release_ctrl_key = KEYBDINPUT {
-                wVk: VK_LCONTROL,
-                wScan: 0x1D,
-                dwFlags: KEYBD_EVENT_FLAGS(0x0002),
+    let key = (
+        VIRTUAL_KEY(kbd_event.vkCode as u16),
+        kbd_event.scanCode as u16,
+        extended,
+    );
+    match kind {
+        KeyEventKind::Press if !PASSED_KEYS.contains(&key) => PASSED_KEYS.push(key),
+        KeyEventKind::Release => PASSED_KEYS.retain(|&k| k != key),
+        _ => {}
+    }
+
+    CallNextHookEx(HHOOK(std::ptr::null_mut()), code, w_param, l_param)
+}
+
+fn key_input((vk, scan, extended): (VIRTUAL_KEY, u16, bool), kind: KeyEventKind) -> INPUT {
+    let mut flags = KEYBD_EVENT_FLAGS(0);
+    if kind == KeyEventKind::Release {
+        flags |= KEYEVENTF_KEYUP;
+    }
+    if extended {
+        flags |= KEYEVENTF_EXTENDEDKEY;
+    }
+
+    INPUT {
+        r#type: INPUT_TYPE(1),
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: vk,
+                wScan: scan,
+                dwFlags: flags,
                 time: 0,
                 dwExtraInfo: 0,
-            };
-            let release_ctrl_input = INPUT {
-                r#type: INPUT_TYPE(1),
-                Anonymous: INPUT_0 {
-                    ki: release_ctrl_key,
-                },
-            };
-            let release_alt_key = KEYBDINPUT {
-                wVk: VK_LMENU,
-                wScan: 0x38,
-                dwFlags: KEYBD_EVENT_FLAGS(0x0002),
+            },
+        },
+    }
+}
+
+fn button_release(button: PointerButton) -> Option<INPUT> {
+    let (flags, data) = match button {
+        PointerButton::Left => (MOUSEEVENTF_LEFTUP, 0),
+        PointerButton::Right => (MOUSEEVENTF_RIGHTUP, 0),
+        PointerButton::Middle => (MOUSEEVENTF_MIDDLEUP, 0),
+        PointerButton::Back => (MOUSEEVENTF_XUP, XBUTTON1),
+        PointerButton::Forward => (MOUSEEVENTF_XUP, XBUTTON2),
+        // Never captured here
+        PointerButton::Side | PointerButton::Extra => return None,
+    };
+
+    Some(INPUT {
+        r#type: INPUT_TYPE(0),
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx: 0,
+                dy: 0,
+                mouseData: data as u32,
+                dwFlags: flags,
                 time: 0,
                 dwExtraInfo: 0,
-            };
-            let release_alt_input = INPUT {
-                r#type: INPUT_TYPE(1),
-                Anonymous: INPUT_0 {
-                    ki: release_alt_key,
-                },
-            };
-            SendInput(
-                &[release_ctrl_input, release_alt_input],
-                std::mem::size_of::<INPUT>() as i32,
-            );
-
-            LRESULT(1)
+            },
+        },
+    })
+}
+
+/// Releases the keys and buttons that went through to Windows and are
+/// still held, last pressed first
+unsafe fn release_passed() {
+    if PASSED_KEYS.is_empty() && PASSED_BUTTONS.is_empty() {
+        return;
+    }
+
+    let keys = PASSED_KEYS
+        .drain(..)
+        .rev()
+        .map(|key| key_input(key, KeyEventKind::Release));
+    let buttons = PASSED_BUTTONS.drain(..).rev().filter_map(button_release);
+    let inputs: Vec<INPUT> = keys.chain(buttons).collect();
+
+    SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+}
+
+/// Makes the lock state of this machine match `locks`, for when it gets
+/// focus back after the locks were toggled on a client
+pub fn sync_locks(locks: Modifiers) {
+    let keys = [
+        (Modifiers::CAPS_LOCK, (VK_CAPITAL, 0x3A, false)),
+        (Modifiers::NUM_LOCK, (VK_NUMLOCK, 0x45, true)),
+        (Modifiers::SCROLL_LOCK, (VK_SCROLL, 0x46, false)),
+    ];
+
+    unsafe {
+        let differ = lock_state() ^ locks;
+        let inputs: Vec<INPUT> = keys
+            .into_iter()
+            .filter(|&(lock, _)| differ.contains(lock))
+            .flat_map(|(_, key)| {
+                [
+                    key_input(key, KeyEventKind::Press),
+                    key_input(key, KeyEventKind::Release),
+                ]
+            })
+            .collect();
+
+        SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+    }
+}
+
+/// The keysym a key types in the layout of this machine, for clients in
+/// keysym mode. Those get key positions from a Windows server for now.
+pub fn keysym(_event: &KeyEvent) -> Option<u32> {
+    // TODO: Resolve through the active layout with ToUnicodeEx
+    None
+}
+
+/// Lock state as Windows sees it, the low bit of GetKeyState is the toggle
+unsafe fn lock_state() -> Modifiers {
+    let toggled = |vk: VIRTUAL_KEY| GetKeyState(vk.0 as i32) & 1 != 0;
+
+    let mut locks = Modifiers::empty();
+    locks.set(Modifiers::CAPS_LOCK, toggled(VK_CAPITAL));
+    locks.set(Modifiers::NUM_LOCK, toggled(VK_NUMLOCK));
+    locks.set(Modifiers::SCROLL_LOCK, toggled(VK_SCROLL));
+
+    locks
+}
+
+unsafe extern "system" fn mouse_hook(code: i32, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
+    let mouse_event: MSLLHOOKSTRUCT = *(l_param.0 as *const _);
+
+    if mouse_event.flags & LLMHF_INJECTED != 0 {
+        return CallNextHookEx(HHOOK(std::ptr::null_mut()), code, w_param, l_param);
+    }
+
+    // The high word of mouseData holds the wheel delta or which X button was used
+    let high_word = (mouse_event.mouseData >> 16) as u16;
+    let wheel_clicks = high_word as i16 as i32 / WHEEL_DELTA as i32;
+    let x_button = if high_word == XBUTTON1 {
+        PointerButton::Back
+    } else {
+        PointerButton::Forward
+    };
+
+    let button = |button, kind| PointerEvent::Button { button, kind };
+    let event = match w_param.0 as u32 {
+        WM_MOUSEMOVE => {
+            let last = LAST_MOUSE_POS.unwrap_or(mouse_event.pt);
+            PointerEvent::Motion {
+                dx: mouse_event.pt.x - last.x,
+                dy: mouse_event.pt.y - last.y,
+            }
         }
+        WM_LBUTTONDOWN => button(PointerButton::Left, KeyEventKind::Press),
+        WM_LBUTTONUP => button(PointerButton::Left, KeyEventKind::Release),
+        WM_RBUTTONDOWN => button(PointerButton::Right, KeyEventKind::Press),
+        WM_RBUTTONUP => button(PointerButton::Right, KeyEventKind::Release),
+        WM_MBUTTONDOWN => button(PointerButton::Middle, KeyEventKind::Press),
+        WM_MBUTTONUP => button(PointerButton::Middle, KeyEventKind::Release),
+        WM_XBUTTONDOWN => button(x_button, KeyEventKind::Press),
+        WM_XBUTTONUP => button(x_button, KeyEventKind::Release),
+        WM_MOUSEWHEEL => PointerEvent::Wheel {
+            dx: 0,
+            dy: wheel_clicks,
+        },
+        WM_MOUSEHWHEEL => PointerEvent::Wheel {
+            dx: wheel_clicks,
+            dy: 0,
+        },
+        _ => return CallNextHookEx(HHOOK(std::ptr::null_mut()), code, w_param, l_param),
+    };
+
+    let cb = GLOBAL_CALLBACK.as_mut().unwrap();
+
+    if cb(Event::Pointer(event)) {
+        release_passed();
+        // Swallowing the move keeps the cursor where it is, so the next
+        // delta is measured from the same spot
+        LRESULT(1)
+    } else {
+        match event {
+            PointerEvent::Motion { .. } => LAST_MOUSE_POS = Some(mouse_event.pt),
+            PointerEvent::Button { button, kind } => match kind {
+                KeyEventKind::Press if !PASSED_BUTTONS.contains(&button) => {
+                    PASSED_BUTTONS.push(button)
+                }
+                KeyEventKind::Release => PASSED_BUTTONS.retain(|&b| b != button),
+                _ => {}
+            },
+            PointerEvent::Wheel { .. } => {}
+        }
+        CallNextHookEx(HHOOK(std::ptr::null_mut()), code, w_param, l_param)
+    }
+}
+
+/// Replaces the focus switching hotkeys
+pub fn set_hotkeys(hotkeys: Vec<HotkeyBinding>) {
+    *NEW_HOTKEYS.lock().unwrap() = Some(hotkeys);
+}
+
+pub fn set_device_filters(filters: DeviceFilters) {
+    warn_device_filters(&filters);
+}
+
+fn warn_device_filters(filters: &DeviceFilters) {
+    // The hooks see the input of all devices merged
+    if !filters.include.is_empty() || !filters.exclude.is_empty() || !filters.deny.is_empty() {
+        log::warn!("Device filters aren't supported on Windows, capturing all devices");
     }
 }
 
-pub fn init<F: FnMut(Event) -> bool + 'static + Send>(callback: F) {
+/// Returns the lock state capture started with
+pub fn init<F: FnMut(Event) -> bool + 'static + Send>(
+    hotkeys: Vec<HotkeyBinding>,
+    filters: &DeviceFilters,
+    // Only used for keysyms, see `keysym`
+    _keyboard_layout: Option<KeyboardLayout>,
+    callback: F,
+) -> Modifiers {
+    warn_device_filters(filters);
+    let locks = unsafe { lock_state() };
+
     thread::spawn(move || {
         unsafe {
             GLOBAL_CALLBACK = Some(Box::new(callback));
+            GLOBAL_MATCHER = Some(HotkeyMatcher::new(hotkeys, locks));
 
             SetWindowsHookExW(
                 WH_KEYBOARD_LL,
@@ -142,6 +396,14 @@ pub fn init<F: FnMut(Event) -> bool + 'static + Send>(callback: F) {
                 0,
             )
             .unwrap();
+
+            SetWindowsHookExW(
+                WH_MOUSE_LL,
+                Some(mouse_hook),
+                HINSTANCE(std::ptr::null_mut()),
+                0,
+            )
+            .unwrap();
         }
 
         unsafe {
@@ -152,4 +414,6 @@ pub fn init<F: FnMut(Event) -> bool + 'static + Send>(callback: F) {
             }
         }
     });
+
+    locks
 }
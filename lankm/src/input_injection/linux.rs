@@ -1,7 +1,17 @@
-use crate::event::{KeyEvent, KeyEventKind};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+
+use crate::config::KeyboardLayout;
+use crate::event::{
+    InputState, KeyEvent, KeyEventKind, KeysymEvent, Modifiers, PointerButton, PointerEvent,
+    UsagePage,
+};
+use crate::xkb::{KeyCombo, Keymap};
 
 use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
-use evdev::AttributeSet;
+use evdev::{AttributeSet, Device, LedType, RelativeAxisType};
+
+pub const VIRTUAL_DEVICE_NAME: &str = "lankm-virtual-dev";
 
 // taken from drives/hid/usbhid/usbkbd.c in the linux 6.10.7 source
 pub(crate) const HID_TO_LINUX_TABLE: [u8; 252] = [
@@ -18,46 +28,435 @@ pub(crate) const HID_TO_LINUX_TABLE: [u8; 252] = [
     177, 178, 176, 142, 152, 173, 140,
 ];
 
+// Consumer page usages, taken from hidinput_configure_usage in
+// drivers/hid/hid-input.c
+pub(crate) const CONSUMER_TO_LINUX: [(u16, evdev::Key); 25] = [
+    (0x006F, evdev::Key::KEY_BRIGHTNESSUP),
+    (0x0070, evdev::Key::KEY_BRIGHTNESSDOWN),
+    (0x00B0, evdev::Key::KEY_PLAY),
+    (0x00B1, evdev::Key::KEY_PAUSE),
+    (0x00B3, evdev::Key::KEY_FASTFORWARD),
+    (0x00B4, evdev::Key::KEY_REWIND),
+    (0x00B5, evdev::Key::KEY_NEXTSONG),
+    (0x00B6, evdev::Key::KEY_PREVIOUSSONG),
+    (0x00B7, evdev::Key::KEY_STOPCD),
+    (0x00B8, evdev::Key::KEY_EJECTCD),
+    (0x00CD, evdev::Key::KEY_PLAYPAUSE),
+    (0x00E2, evdev::Key::KEY_MUTE),
+    (0x00E9, evdev::Key::KEY_VOLUMEUP),
+    (0x00EA, evdev::Key::KEY_VOLUMEDOWN),
+    (0x0183, evdev::Key::KEY_CONFIG),
+    (0x018A, evdev::Key::KEY_MAIL),
+    (0x0192, evdev::Key::KEY_CALC),
+    (0x0194, evdev::Key::KEY_FILE),
+    (0x0221, evdev::Key::KEY_SEARCH),
+    (0x0223, evdev::Key::KEY_HOMEPAGE),
+    (0x0224, evdev::Key::KEY_BACK),
+    (0x0225, evdev::Key::KEY_FORWARD),
+    (0x0226, evdev::Key::KEY_STOP),
+    (0x0227, evdev::Key::KEY_REFRESH),
+    (0x022A, evdev::Key::KEY_BOOKMARKS),
+];
+
+// System Control usages of the Generic Desktop page
+pub(crate) const SYSTEM_CONTROL_TO_LINUX: [(u16, evdev::Key); 3] = [
+    (0x0081, evdev::Key::KEY_POWER),
+    (0x0082, evdev::Key::KEY_SLEEP),
+    (0x0083, evdev::Key::KEY_WAKEUP),
+];
+
+/// The Linux key for a usage, if there's one
+pub(crate) fn usage_to_linux(page: UsagePage, hid: u16) -> Option<evdev::Key> {
+    let table: &[(u16, evdev::Key)] = match page {
+        UsagePage::Keyboard => {
+            return HID_TO_LINUX_TABLE
+                .get(hid as usize)
+                .filter(|&&code| code != 0)
+                .map(|&code| evdev::Key::new(code as u16))
+        }
+        UsagePage::Consumer => &CONSUMER_TO_LINUX,
+        UsagePage::GenericDesktop => &SYSTEM_CONTROL_TO_LINUX,
+    };
+
+    table
+        .iter()
+        .find(|&&(usage, _)| usage == hid)
+        .map(|&(_, key)| key)
+}
+
+pub(crate) const fn button_to_linux(button: PointerButton) -> evdev::Key {
+    match button {
+        PointerButton::Left => evdev::Key::BTN_LEFT,
+        PointerButton::Right => evdev::Key::BTN_RIGHT,
+        PointerButton::Middle => evdev::Key::BTN_MIDDLE,
+        PointerButton::Side => evdev::Key::BTN_SIDE,
+        PointerButton::Extra => evdev::Key::BTN_EXTRA,
+        PointerButton::Forward => evdev::Key::BTN_FORWARD,
+        PointerButton::Back => evdev::Key::BTN_BACK,
+    }
+}
+
+const BUTTONS: [PointerButton; 7] = [
+    PointerButton::Left,
+    PointerButton::Right,
+    PointerButton::Middle,
+    PointerButton::Side,
+    PointerButton::Extra,
+    PointerButton::Forward,
+    PointerButton::Back,
+];
+
+/// Value of an evdev key event
+const fn key_value(kind: KeyEventKind) -> i32 {
+    match kind {
+        KeyEventKind::Release => 0,
+        KeyEventKind::Press => 1,
+        KeyEventKind::Repeat => 2,
+    }
+}
+
+/// Lock state as shown by a keyboard's LEDs
+pub(crate) fn lock_state(device: &Device) -> Option<Modifiers> {
+    let leds = device.get_led_state().ok()?;
+
+    let mut locks = Modifiers::empty();
+    locks.set(Modifiers::CAPS_LOCK, leds.contains(LedType::LED_CAPSL));
+    locks.set(Modifiers::NUM_LOCK, leds.contains(LedType::LED_NUML));
+    locks.set(Modifiers::SCROLL_LOCK, leds.contains(LedType::LED_SCROLLL));
+
+    Some(locks)
+}
+
+/// Lock state of this seat, read from the first keyboard with LEDs. The
+/// kernel keeps the LEDs of all keyboards in step.
+fn seat_locks() -> Option<Modifiers> {
+    evdev::enumerate()
+        .map(|(_, device)| device)
+        .filter(|device| device.name() != Some(VIRTUAL_DEVICE_NAME))
+        .filter(|device| {
+            device
+                .supported_leds()
+                .is_some_and(|leds| leds.contains(LedType::LED_CAPSL))
+        })
+        .find_map(|device| lock_state(&device))
+}
+
 pub struct InputInjector {
     virtual_device: VirtualDevice,
+    /// Layout to type with instead of the one configured on this machine
+    keyboard_layout: Option<KeyboardLayout>,
+    /// Loaded when the first text or keysym arrives, None if that failed
+    keymap: OnceCell<Option<Keymap>>,
+    /// The key each held keysym was typed with
+    keysyms_down: HashMap<u32, u16>,
+    /// Key and button codes held on the virtual device, in press order
+    pressed: Vec<u16>,
 }
 
 impl InputInjector {
-    pub fn new() -> Self {
+    pub fn new(keyboard_layout: Option<KeyboardLayout>) -> Self {
         let keys = &mut AttributeSet::<evdev::Key>::new();
         for i in 0..256 {
             keys.insert(evdev::Key::new(i));
         }
+        for button in BUTTONS {
+            keys.insert(button_to_linux(button));
+        }
+
+        let axes = &mut AttributeSet::<RelativeAxisType>::new();
+        axes.insert(RelativeAxisType::REL_X);
+        axes.insert(RelativeAxisType::REL_Y);
+        axes.insert(RelativeAxisType::REL_WHEEL);
+        axes.insert(RelativeAxisType::REL_HWHEEL);
 
         let virtual_device = VirtualDeviceBuilder::new()
             .unwrap()
-            .name("lankm-virtual-dev")
+            .name(VIRTUAL_DEVICE_NAME)
             .with_keys(keys)
             .unwrap()
+            .with_relative_axes(axes)
+            .unwrap()
             .build()
             .unwrap();
 
-        Self { virtual_device }
+        Self {
+            virtual_device,
+            keyboard_layout,
+            keymap: OnceCell::new(),
+            keysyms_down: HashMap::new(),
+            pressed: Vec::new(),
+        }
+    }
+
+    /// Emits a key or button event on its own, keeping track of what's held
+    fn emit_key(&mut self, code: u16, value: i32) {
+        match value {
+            0 => self.pressed.retain(|&c| c != code),
+            1 if !self.pressed.contains(&code) => self.pressed.push(code),
+            _ => {}
+        }
+
+        let events = &[evdev::InputEvent::new(evdev::EventType::KEY, code, value)];
+        self.virtual_device.emit(events).unwrap();
+    }
+
+    fn keymap(&self) -> Option<&Keymap> {
+        self.keymap
+            .get_or_init(|| match Keymap::load(self.keyboard_layout.as_ref()) {
+                Ok(keymap) => Some(keymap),
+                Err(e) => {
+                    log::error!("Can't use the keyboard layout, {}", e);
+                    None
+                }
+            })
+            .as_ref()
     }
 
     pub fn emit(&mut self, event: KeyEvent) {
-        let value = match event.kind {
-            KeyEventKind::Release => 0,
-            KeyEventKind::Press => 1,
+        let value = key_value(event.kind);
+        let Some(key) = usage_to_linux(event.page, event.hid) else {
+            log::warn!(
+                "Dropping key with unknown usage {:#x} on page {:?}",
+                event.hid,
+                event.page
+            );
+            return;
         };
-        let scancode = HID_TO_LINUX_TABLE[event.hid as usize];
 
-        let events = &[evdev::InputEvent::new(
-            evdev::EventType::KEY,
-            scancode as u16,
-            value,
-        )];
-        self.virtual_device.emit(events).unwrap();
+        self.emit_key(key.code(), value);
+    }
+
+    pub fn emit_pointer(&mut self, event: PointerEvent) {
+        let rel = |axis: RelativeAxisType, value| {
+            evdev::InputEvent::new(evdev::EventType::RELATIVE, axis.0, value)
+        };
+
+        let events = match event {
+            PointerEvent::Motion { dx, dy } => {
+                vec![
+                    rel(RelativeAxisType::REL_X, dx),
+                    rel(RelativeAxisType::REL_Y, dy),
+                ]
+            }
+            PointerEvent::Button { button, kind } => {
+                self.emit_key(button_to_linux(button).code(), key_value(kind));
+                return;
+            }
+            PointerEvent::Wheel { dx, dy } => vec![
+                rel(RelativeAxisType::REL_HWHEEL, dx),
+                rel(RelativeAxisType::REL_WHEEL, dy),
+            ],
+        };
+
+        // Skip zero axes so we don't wake up the compositor for nothing
+        let events: Vec<_> = events
+            .into_iter()
+            .filter(|e| e.event_type() != evdev::EventType::RELATIVE || e.value() != 0)
+            .collect();
+        if events.is_empty() {
+            return;
+        }
+        self.virtual_device.emit(&events).unwrap();
+    }
+
+    /// Types text through the keyboard layout of this machine
+    pub fn emit_text(&mut self, text: &str) {
+        let Some(keymap) = self.keymap() else {
+            return;
+        };
+
+        let mut combos = Vec::new();
+        for c in text.chars() {
+            match keymap.type_char(c) {
+                Some(keys) => combos.extend(keys),
+                None => log::warn!("Can't type {:?} with this keyboard layout", c),
+            }
+        }
+
+        // CapsLock would flip the case of letters typed with or without
+        // Shift, turn it off while typing
+        let caps_lock = seat_locks().is_some_and(|locks| locks.contains(Modifiers::CAPS_LOCK));
+        if caps_lock {
+            self.toggle_locks(Modifiers::CAPS_LOCK);
+        }
+        for combo in combos {
+            self.emit_combo(combo);
+        }
+        if caps_lock {
+            self.toggle_locks(Modifiers::CAPS_LOCK);
+        }
+    }
+
+    /// Presses or releases whichever key types the keysym in the layout of
+    /// this machine, falling back to the key the server pressed
+    pub fn emit_keysym(&mut self, event: KeysymEvent) {
+        let combo = self.keymap().and_then(|keymap| {
+            keymap
+                .keysym_combo(event.keysym)
+                .map(|c| (c, keymap.has_altgr()))
+        });
+        let Some((combo, altgr)) = combo else {
+            self.emit(event.key);
+            return;
+        };
+
+        match event.key.kind {
+            KeyEventKind::Press => {
+                // The server's modifiers are held here too, fix up the ones
+                // this layout needs differently for the key and put them
+                // back afterwards
+                let mods = event.key.mods;
+                let mut fixups = Vec::new();
+                if combo.shift && !mods.intersects(Modifiers::SHIFT) {
+                    fixups.push((evdev::Key::KEY_LEFTSHIFT.code(), 1));
+                }
+                if !combo.shift {
+                    if mods.contains(Modifiers::LSHIFT) {
+                        fixups.push((evdev::Key::KEY_LEFTSHIFT.code(), 0));
+                    }
+                    if mods.contains(Modifiers::RSHIFT) {
+                        fixups.push((evdev::Key::KEY_RIGHTSHIFT.code(), 0));
+                    }
+                }
+                if altgr && combo.level3 != mods.contains(Modifiers::ALTGR) {
+                    fixups.push((evdev::Key::KEY_RIGHTALT.code(), combo.level3 as i32));
+                }
+
+                for &(code, value) in &fixups {
+                    self.emit_key(code, value);
+                }
+                self.emit_key(combo.key, 1);
+                for &(code, value) in fixups.iter().rev() {
+                    self.emit_key(code, 1 - value);
+                }
+                self.keysyms_down.insert(event.keysym, combo.key);
+            }
+            KeyEventKind::Repeat => {
+                let code = self.keysyms_down.get(&event.keysym).copied();
+                self.emit_key(code.unwrap_or(combo.key), 2);
+            }
+            KeyEventKind::Release => {
+                let code = self.keysyms_down.remove(&event.keysym);
+                self.emit_key(code.unwrap_or(combo.key), 0);
+            }
+        }
+    }
+
+    fn emit_combo(&mut self, combo: KeyCombo) {
+        let mut keys = Vec::new();
+        if combo.ctrl {
+            keys.push(evdev::Key::KEY_LEFTCTRL.code());
+        }
+        if combo.shift {
+            keys.push(evdev::Key::KEY_LEFTSHIFT.code());
+        }
+        if combo.level3 {
+            keys.push(evdev::Key::KEY_RIGHTALT.code());
+        }
+        keys.push(combo.key);
+
+        // Each in its own report, some toolkits miss a press and release
+        // of the same key in one
+        for &code in &keys {
+            self.emit_key(code, 1);
+        }
+        for &code in keys.iter().rev() {
+            self.emit_key(code, 0);
+        }
+    }
+
+    /// Presses and releases the keys of the given locks, flipping them
+    pub fn toggle_locks(&mut self, locks: Modifiers) {
+        for hid in locks.lock_keys() {
+            for kind in [KeyEventKind::Press, KeyEventKind::Release] {
+                self.emit(KeyEvent {
+                    page: UsagePage::Keyboard,
+                    hid,
+                    kind,
+                    mods: Modifiers::empty(),
+                });
+            }
+        }
+    }
+
+    /// Toggles whichever locks of this machine differ from `locks`
+    fn sync_locks(&mut self, locks: Modifiers) {
+        let Some(current) = seat_locks() else {
+            log::warn!("Could not read the keyboard LEDs, lock state is not synced");
+            return;
+        };
+
+        let differ = (current ^ locks) & Modifiers::LOCKS;
+        if !differ.is_empty() {
+            log::debug!("Toggling {:?} to match the server", differ);
+            self.toggle_locks(differ);
+        }
     }
 
+    /// Presses and releases keys until exactly the keys of `state` are
+    /// held, and matches its locks. Buttons are let go of.
+    pub fn sync_state(&mut self, state: &InputState) {
+        let modifiers = state.mods.held_keys().map(|hid| (UsagePage::Keyboard, hid));
+        let mut wanted: Vec<u16> = Vec::new();
+        for (page, hid) in state.keys.iter().copied().chain(modifiers) {
+            match usage_to_linux(page, hid) {
+                Some(key) if !wanted.contains(&key.code()) => wanted.push(key.code()),
+                _ => {}
+            }
+        }
+
+        let stale: Vec<u16> = self
+            .pressed
+            .iter()
+            .rev()
+            .copied()
+            .filter(|code| !wanted.contains(code))
+            .collect();
+        if !stale.is_empty() {
+            log::debug!("Releasing {:?} to match the server", stale);
+        }
+        for code in stale {
+            self.emit_key(code, 0);
+        }
+        self.keysyms_down
+            .retain(|_, code| self.pressed.contains(code));
+
+        for code in wanted {
+            if !self.pressed.contains(&code) {
+                log::debug!("Pressing {:?} to match the server", evdev::Key::new(code));
+                self.emit_key(code, 1);
+            }
+        }
+
+        self.sync_locks(state.mods);
+    }
+
+    /// Whether any key or button is held
+    pub fn is_holding(&self) -> bool {
+        !self.pressed.is_empty()
+    }
+
+    /// Names of the held keys and buttons
+    pub fn held(&self) -> Vec<String> {
+        self.pressed
+            .iter()
+            .map(|&code| format!("{:?}", evdev::Key::new(code)))
+            .collect()
+    }
+
+    /// Releases every key and button still held, last pressed first
     pub fn release_all(&mut self) {
-        let events: Vec<_> = (0..256)
-            .map(|i| evdev::InputEvent::new(evdev::EventType::KEY, i, 0))
+        self.keysyms_down.clear();
+        let pressed = std::mem::take(&mut self.pressed);
+        if pressed.is_empty() {
+            return;
+        }
+
+        log::debug!("Releasing held keys {:?}", pressed);
+        let events: Vec<_> = pressed
+            .into_iter()
+            .rev()
+            .map(|code| evdev::InputEvent::new(evdev::EventType::KEY, code, 0))
             .collect();
         self.virtual_device.emit(&events).unwrap();
     }
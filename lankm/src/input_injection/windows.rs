@@ -1,9 +1,10 @@
-use crate::event::KeyEvent;
+use crate::config::KeyboardLayout;
+use crate::event::{InputState, KeyEvent, KeysymEvent, PointerEvent};
 
 pub struct InputInjector {}
 
 impl InputInjector {
-    pub fn new() -> Self {
+    pub fn new(_keyboard_layout: Option<KeyboardLayout>) -> Self {
         todo!();
     }
 
@@ -11,6 +12,30 @@ impl InputInjector {
         todo!();
     }
 
+    pub fn emit_pointer(&mut self, _e: PointerEvent) {
+        todo!();
+    }
+
+    pub fn emit_text(&mut self, _text: &str) {
+        todo!();
+    }
+
+    pub fn emit_keysym(&mut self, _e: KeysymEvent) {
+        todo!();
+    }
+
+    pub fn sync_state(&mut self, _state: &InputState) {
+        todo!();
+    }
+
+    pub fn is_holding(&self) -> bool {
+        todo!();
+    }
+
+    pub fn held(&self) -> Vec<String> {
+        todo!();
+    }
+
     pub fn release_all(&mut self) {
         todo!();
     }
@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Name of the screen attached to the server itself
+pub const LOCAL_SCREEN: &str = "server";
+
+const DEFAULT_SCREEN_SIZE: (i32, i32) = (1920, 1080);
+
+/// Refuses the names a client can't go by: the local screen's, which would
+/// share its slot in the layout, and the ones hotkeys can't target
+pub fn check_client_name(name: &str) -> Result<(), String> {
+    if name == LOCAL_SCREEN || crate::hotkey::RESERVED_TARGETS.contains(&name) {
+        return Err(format!(
+            "'{}' is reserved and can't be the name of a client",
+            name
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Direction {
+    fn opposite(self) -> Self {
+        match self {
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Direction::Left => "left",
+            Direction::Right => "right",
+            Direction::Up => "top",
+            Direction::Down => "bottom",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "left" => Ok(Direction::Left),
+            "right" => Ok(Direction::Right),
+            "above" | "up" => Ok(Direction::Up),
+            "below" | "down" => Ok(Direction::Down),
+            _ => Err(format!(
+                "invalid direction '{}', expected left, right, above or below",
+                s
+            )),
+        }
+    }
+}
+
+/// A `NAME=WIDTHxHEIGHT` screen definition from the command line
+#[derive(Clone, Debug)]
+pub struct ScreenArg {
+    pub name: String,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl FromStr for ScreenArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid screen '{}', expected NAME=WIDTHxHEIGHT", s);
+
+        let (name, size) = s.split_once('=').ok_or_else(err)?;
+        let (width, height) = size.split_once('x').ok_or_else(err)?;
+        let width: i32 = width.parse().map_err(|_| err())?;
+        let height: i32 = height.parse().map_err(|_| err())?;
+
+        if name.is_empty() || width <= 0 || height <= 0 {
+            return Err(err());
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            width,
+            height,
+        })
+    }
+}
+
+/// A `SCREEN:DIRECTION:OTHER` placement from the command line, read as
+/// "SCREEN is DIRECTION of OTHER", e.g. `laptop:left:server`
+#[derive(Clone, Debug)]
+pub struct NeighborArg {
+    pub screen: String,
+    pub direction: Direction,
+    pub of: String,
+}
+
+impl FromStr for NeighborArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split(':').collect();
+        match parts[..] {
+            [screen, direction, of] if !screen.is_empty() && !of.is_empty() => Ok(Self {
+                screen: screen.to_owned(),
+                direction: direction.parse()?,
+                of: of.to_owned(),
+            }),
+            _ => Err(format!(
+                "invalid neighbor '{}', expected SCREEN:DIRECTION:OTHER",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Screen {
+    name: String,
+    width: i32,
+    height: i32,
+}
+
+/// How the screens of the server and its clients are placed relative to
+/// each other. The local screen is always present as screen 0.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    screens: Vec<Screen>,
+    neighbors: HashMap<(usize, Direction), usize>,
+}
+
+impl Layout {
+    pub fn new(screens: &[ScreenArg], neighbors: &[NeighborArg]) -> Result<Self, String> {
+        let mut layout = Self {
+            screens: Vec::new(),
+            neighbors: HashMap::new(),
+        };
+        layout.add_screen(LOCAL_SCREEN);
+
+        for screen in screens {
+            let index = layout.add_screen(&screen.name);
+            layout.screens[index].width = screen.width;
+            layout.screens[index].height = screen.height;
+        }
+
+        for neighbor in neighbors {
+            if neighbor.screen == neighbor.of {
+                return Err(format!(
+                    "screen {} can't be its own neighbor",
+                    neighbor.screen
+                ));
+            }
+
+            let a = layout.add_screen(&neighbor.screen);
+            let b = layout.add_screen(&neighbor.of);
+
+            // "a is left of b" also means "b is right of a"
+            layout.link(b, neighbor.direction, a)?;
+            layout.link(a, neighbor.direction.opposite(), b)?;
+        }
+
+        Ok(layout)
+    }
+
+    fn add_screen(&mut self, name: &str) -> usize {
+        if let Some(index) = self.index_of(name) {
+            return index;
+        }
+
+        self.screens.push(Screen {
+            name: name.to_owned(),
+            width: DEFAULT_SCREEN_SIZE.0,
+            height: DEFAULT_SCREEN_SIZE.1,
+        });
+        self.screens.len() - 1
+    }
+
+    fn link(&mut self, from: usize, direction: Direction, to: usize) -> Result<(), String> {
+        match self.neighbors.insert((from, direction), to) {
+            Some(existing) if existing != to => Err(format!(
+                "{} already has {} on its {} side, can't place {} there too",
+                self.screens[from].name,
+                self.screens[existing].name,
+                direction,
+                self.screens[to].name
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.screens.iter().position(|s| s.name == name)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Focus {
+    Local,
+    Remote(String),
+}
+
+impl fmt::Display for Focus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Focus::Local => write!(f, "{}", LOCAL_SCREEN),
+            Focus::Remote(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Decides which screen receives input. Pointer motion moves a virtual
+/// cursor around the focused screen, and crossing an edge that has a
+/// connected neighbor hands focus over to that neighbor.
+///
+/// The cursor is only an approximation: we see raw device deltas, while the
+/// desktops apply their own pointer acceleration.
+pub struct FocusTracker {
+    layout: Layout,
+    // Indexed like layout.screens, the local screen is always connected
+    connected: Vec<bool>,
+    current: usize,
+    cursor: (i32, i32),
+}
+
+impl FocusTracker {
+    pub fn new(layout: Layout) -> Self {
+        let mut connected = vec![false; layout.screens.len()];
+        connected[0] = true;
+
+        let mut tracker = Self {
+            layout,
+            connected,
+            current: 0,
+            cursor: (0, 0),
+        };
+        tracker.center_cursor();
+
+        tracker
+    }
+
+    /// Marks a client as connected. Clients missing from the layout get a
+    /// screen without neighbors, which can still be reached with hotkeys.
+    pub fn connect(&mut self, name: &str) {
+        let index = self.layout.add_screen(name);
+        // The local screen, see `check_client_name`
+        if index == 0 {
+            return;
+        }
+        self.connected.resize(self.layout.screens.len(), false);
+        self.connected[index] = true;
+    }
+
+    /// Marks a client as gone, handing focus back to the local screen if
+    /// the client had it
+    pub fn disconnect(&mut self, name: &str) -> Option<Focus> {
+        let index = self.layout.index_of(name).filter(|&i| i != 0)?;
+        self.connected[index] = false;
+
+        if self.current != index {
+            return None;
+        }
+        self.current = 0;
+        self.center_cursor();
+
+        Some(self.focus())
+    }
+
+    pub fn focus(&self) -> Focus {
+        match self.current {
+            0 => Focus::Local,
+            n => Focus::Remote(self.layout.screens[n].name.clone()),
+        }
+    }
+
+    pub fn switch_local(&mut self) -> Option<Focus> {
+        self.switch_to_screen(0)
+    }
+
+    /// Gives focus to the named client, if it's connected
+    pub fn switch_to(&mut self, name: &str) -> Option<Focus> {
+        let index = self.layout.index_of(name)?;
+        self.switch_to_screen(index)
+    }
+
+    /// Moves focus to the next connected screen, in layout order, wrapping
+    /// around back to the local screen
+    pub fn next(&mut self) -> Option<Focus> {
+        let count = self.connected.len();
+        let index = (1..count)
+            .map(|offset| (self.current + offset) % count)
+            .find(|&i| self.connected[i])?;
+
+        self.switch_to_screen(index)
+    }
+
+    fn switch_to_screen(&mut self, index: usize) -> Option<Focus> {
+        if index == self.current || !self.connected[index] {
+            return None;
+        }
+
+        self.current = index;
+        self.center_cursor();
+
+        Some(self.focus())
+    }
+
+    /// Moves the virtual cursor, returning the new focus if it crossed over
+    /// to a neighboring screen
+    pub fn motion(&mut self, dx: i32, dy: i32) -> Option<Focus> {
+        let screen = &self.layout.screens[self.current];
+        let (x, y) = (
+            self.cursor.0.saturating_add(dx),
+            self.cursor.1.saturating_add(dy),
+        );
+
+        let crossed = if x < 0 {
+            Some(Direction::Left)
+        } else if x >= screen.width {
+            Some(Direction::Right)
+        } else if y < 0 {
+            Some(Direction::Up)
+        } else if y >= screen.height {
+            Some(Direction::Down)
+        } else {
+            None
+        };
+
+        let next = crossed
+            .and_then(|d| Some((d, *self.layout.neighbors.get(&(self.current, d))?)))
+            .filter(|&(_, next)| self.connected[next]);
+        let Some((direction, next)) = next else {
+            self.cursor = (x.clamp(0, screen.width - 1), y.clamp(0, screen.height - 1));
+            return None;
+        };
+
+        // Enter on the opposite edge, keeping the relative position along it
+        let (width, height) = (screen.width, screen.height);
+        let target = &self.layout.screens[next];
+        let along_y = scale(y.clamp(0, height - 1), height, target.height);
+        let along_x = scale(x.clamp(0, width - 1), width, target.width);
+        self.cursor = match direction {
+            Direction::Left => (target.width - 1, along_y),
+            Direction::Right => (0, along_y),
+            Direction::Up => (along_x, target.height - 1),
+            Direction::Down => (along_x, 0),
+        };
+        self.current = next;
+
+        Some(self.focus())
+    }
+
+    fn center_cursor(&mut self) {
+        let screen = &self.layout.screens[self.current];
+        self.cursor = (screen.width / 2, screen.height / 2);
+    }
+}
+
+/// `position` on an edge `from` long, moved to the same relative spot on an
+/// edge `to` long. In i64 since large screens overflow the product.
+fn scale(position: i32, from: i32, to: i32) -> i32 {
+    (position as i64 * to as i64 / from as i64) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(screens: &[&str], neighbors: &[&str]) -> Result<Layout, String> {
+        let screens: Vec<ScreenArg> = screens.iter().map(|s| s.parse().unwrap()).collect();
+        let neighbors: Vec<NeighborArg> = neighbors.iter().map(|n| n.parse().unwrap()).collect();
+        Layout::new(&screens, &neighbors)
+    }
+
+    fn remote(name: &str) -> Option<Focus> {
+        Some(Focus::Remote(name.to_owned()))
+    }
+
+    // laptop (1000x500) | server (2000x1000) | desktop (default size),
+    // tablet (800x600) below the server
+    fn tracker() -> FocusTracker {
+        let layout = layout(
+            &["server=2000x1000", "laptop=1000x500", "tablet=800x600"],
+            &[
+                "laptop:left:server",
+                "desktop:right:server",
+                "tablet:below:server",
+            ],
+        )
+        .unwrap();
+        let mut tracker = FocusTracker::new(layout);
+        for name in ["laptop", "desktop", "tablet"] {
+            tracker.connect(name);
+        }
+        tracker
+    }
+
+    #[test]
+    fn parses_args() {
+        let screen: ScreenArg = "laptop=1920x1200".parse().unwrap();
+        assert_eq!(
+            (screen.name.as_str(), screen.width, screen.height),
+            ("laptop", 1920, 1200)
+        );
+        for invalid in [
+            "laptop",
+            "=1x1",
+            "laptop=1920",
+            "laptop=0x10",
+            "laptop=-5x10",
+            "a=1x1x1",
+        ] {
+            assert!(invalid.parse::<ScreenArg>().is_err(), "{}", invalid);
+        }
+
+        let neighbor: NeighborArg = "laptop:above:server".parse().unwrap();
+        assert_eq!(neighbor.direction, Direction::Up);
+        for invalid in [
+            "laptop:left",
+            ":left:server",
+            "laptop:left:",
+            "laptop:inside:server",
+            "a:left:b:c",
+        ] {
+            assert!(invalid.parse::<NeighborArg>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_conflicting_neighbors() {
+        assert!(layout(&[], &["laptop:left:laptop"]).is_err());
+        assert!(layout(&[], &["laptop:left:server", "desktop:left:server"]).is_err());
+        // The same placement said from both sides is fine
+        assert!(layout(&[], &["laptop:left:server", "server:right:laptop"]).is_ok());
+    }
+
+    #[test]
+    fn crosses_edges_with_neighbors() {
+        let mut tracker = tracker();
+        assert_eq!(tracker.focus(), Focus::Local);
+
+        // Cursor starts at (1000, 500), the top edge has no neighbor
+        assert_eq!(tracker.motion(0, -600), None);
+        assert_eq!(tracker.cursor, (1000, 0));
+        assert_eq!(tracker.motion(-1000, 500), None);
+        assert_eq!(tracker.motion(-1, 0), remote("laptop"));
+        // Entered on its right edge, at the same height relative to the edge
+        assert_eq!(tracker.cursor, (999, 250));
+
+        assert_eq!(tracker.motion(0, 0), None);
+        assert_eq!(tracker.motion(1, 0), Some(Focus::Local));
+        assert_eq!(tracker.cursor, (0, 500));
+
+        assert_eq!(tracker.motion(1000, 500), remote("tablet"));
+        assert_eq!(tracker.cursor, (400, 0));
+        assert_eq!(tracker.motion(0, -1), Some(Focus::Local));
+        assert_eq!(tracker.cursor, (1000, 999));
+
+        assert_eq!(tracker.motion(5000, 0), remote("desktop"));
+        assert_eq!(tracker.cursor, (0, 1078));
+    }
+
+    #[test]
+    fn stays_put_without_connected_neighbor() {
+        let mut tracker = tracker();
+        tracker.disconnect("laptop");
+
+        assert_eq!(tracker.motion(-5000, 0), None);
+        assert_eq!(tracker.cursor, (0, 500));
+        assert_eq!(tracker.motion(i32::MIN, i32::MAX), None);
+        assert_eq!(tracker.cursor, (0, 999));
+
+        tracker.connect("laptop");
+        assert_eq!(tracker.motion(-1, 0), remote("laptop"));
+    }
+
+    #[test]
+    fn crosses_between_huge_screens() {
+        let layout = layout(
+            &["server=50000x50000", "wall=2000000000x2000000000"],
+            &["wall:below:server"],
+        )
+        .unwrap();
+        let mut tracker = FocusTracker::new(layout);
+        tracker.connect("wall");
+
+        assert_eq!(tracker.motion(24999, 25000), remote("wall"));
+        assert_eq!(tracker.cursor, (1999960000, 0));
+        assert_eq!(tracker.motion(0, -1), Some(Focus::Local));
+        assert_eq!(tracker.cursor, (49999, 49999));
+    }
+
+    #[test]
+    fn switches_and_cycles() {
+        let mut tracker = tracker();
+        tracker.connect("unplaced");
+
+        assert_eq!(tracker.switch_to("tablet"), remote("tablet"));
+        assert_eq!(tracker.switch_to("tablet"), None);
+        assert_eq!(tracker.switch_to("unknown"), None);
+        assert_eq!(tracker.next(), remote("desktop"));
+        assert_eq!(tracker.next(), remote("unplaced"));
+        assert_eq!(tracker.next(), Some(Focus::Local));
+        assert_eq!(tracker.next(), remote("laptop"));
+
+        tracker.disconnect("desktop");
+        assert_eq!(tracker.next(), remote("tablet"));
+        assert_eq!(tracker.disconnect("laptop"), None);
+        assert_eq!(tracker.disconnect("tablet"), Some(Focus::Local));
+        assert_eq!(tracker.switch_local(), None);
+        assert_eq!(tracker.switch_to("tablet"), None);
+    }
+
+    #[test]
+    fn reserved_names() {
+        for name in [LOCAL_SCREEN, "local", "next"] {
+            assert!(check_client_name(name).is_err(), "{}", name);
+        }
+        assert!(check_client_name("laptop").is_ok());
+
+        // Even if one slipped through, the local screen stays reachable
+        let mut tracker = tracker();
+        tracker.connect(LOCAL_SCREEN);
+        assert_eq!(tracker.switch_to("laptop"), remote("laptop"));
+        assert_eq!(tracker.disconnect(LOCAL_SCREEN), None);
+        assert_eq!(tracker.switch_local(), Some(Focus::Local));
+        assert_eq!(tracker.next(), remote("laptop"));
+        assert_eq!(tracker.next(), remote("tablet"));
+    }
+
+    #[test]
+    fn next_without_clients() {
+        let mut tracker = FocusTracker::new(layout(&[], &[]).unwrap());
+        assert_eq!(tracker.next(), None);
+        assert_eq!(tracker.focus(), Focus::Local);
+    }
+}
@@ -1,21 +1,152 @@
-use std::io::{Read, Write};
-use std::net::{self, IpAddr, SocketAddr, TcpStream};
-use std::sync::mpsc::{self, TryRecvError};
-use std::thread;
+use std::net;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::Parser;
 
+mod client;
+mod config;
+mod device_filter;
 mod event;
+mod hotkey;
 mod input_capture;
 mod input_injection;
+mod layout;
+mod pairing;
+mod peers;
+mod server;
+mod tls;
+#[cfg(target_os = "linux")]
+mod xkb;
 
-use event::{Event, KeyEvent, KeyEventKind, Modifiers};
+/// How long a peer gets to complete the Hello/HelloAck exchange
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+use config::{ClientConfig, Config, KeyboardLayout, ServerConfig};
+use device_filter::DevicePattern;
+use hotkey::HotkeyBinding;
+use layout::{Layout, NeighborArg, ScreenArg};
+
+#[derive(clap::Args, Clone, Debug)]
+struct ClientArgs {
+    /// Address of the server, `address` under `[client]` in the config file
+    address: Option<net::Ipv4Addr>,
+    /// Port of the server, defaults to 6000
+    port: Option<u16>,
+    /// Name the server knows this machine by, defaults to the hostname
+    #[arg(long)]
+    name: Option<String>,
+    /// Only trust a server certificate with this SHA-256 fingerprint, as
+    /// printed by `lankm-headless fingerprint` on the server. By default
+    /// any server this machine paired with is trusted
+    #[arg(long)]
+    fingerprint: Option<String>,
+    /// Connect without encryption, anyone on the network can read the keystrokes
+    #[arg(long, conflicts_with = "fingerprint")]
+    plaintext: bool,
+    /// Give up on the server after this many seconds without hearing from
+    /// it. Must be longer than the server's heartbeat interval. Defaults
+    /// to 5
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    heartbeat_timeout: Option<Duration>,
+    /// Release keys still held after this many seconds without hearing
+    /// from the server. Must be longer than the server's heartbeat
+    /// interval. Defaults to 2
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    stuck_key_timeout: Option<Duration>,
+    /// Who repeats held keys: `forward` the server keyboard's repeats,
+    /// repeat here with `DELAY:RATE` in milliseconds and repeats per
+    /// second, e.g. `500:25`, or `off` to leave it to the desktop.
+    /// Defaults to `forward`. Linux desktops ignore forwarded and
+    /// generated repeats and repeat held keys themselves, only the
+    /// console sees them
+    #[arg(long, value_name = "POLICY")]
+    key_repeat: Option<client::RepeatPolicy>,
+    /// How keys are sent: `scancode` by position, typing whatever the
+    /// key types here, or `keysym` to type what the key types in the
+    /// server's keyboard layout. Defaults to `scancode`
+    #[arg(long, value_name = "MODE")]
+    key_mode: Option<client::KeyMode>,
+    /// XKB layout text and keysyms are typed with, e.g. `de(nodeadkeys)`.
+    /// Defaults to the system's layout, set this if the desktop uses
+    /// another one
+    #[arg(long, value_name = "LAYOUT[(VARIANT)]")]
+    keyboard_layout: Option<KeyboardLayout>,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+struct ServerArgs {
+    /// Port to listen on, defaults to 6000
+    port: Option<u16>,
+    /// Address to listen on, defaults to all of them
+    #[arg(long, value_name = "ADDRESS")]
+    bind: Option<net::IpAddr>,
+    /// Size of a screen, e.g. `server=2560x1440`. Screens default to 1920x1080
+    #[arg(long = "screen", value_name = "NAME=WIDTHxHEIGHT")]
+    screens: Vec<ScreenArg>,
+    /// Placement of a screen relative to another, e.g. `laptop:left:server`
+    /// places the client "laptop" to the left of this machine
+    #[arg(long = "neighbor", value_name = "SCREEN:DIRECTION:OTHER")]
+    neighbors: Vec<NeighborArg>,
+    /// Focus switching hotkey, e.g. `ctrl+alt+1=build-box`. Modifiers are
+    /// ctrl, shift, alt, super and altgr, prefix them with l or r to only
+    /// accept one side. TARGET is a client name, `local` or `next`.
+    /// Defaults to `ctrl+alt+tab=next`
+    #[arg(long = "hotkey", value_name = "KEYS=TARGET")]
+    hotkeys: Vec<HotkeyBinding>,
+    /// Only capture input devices matching this. A pattern is part of the
+    /// device name, `id:VENDOR[:PRODUCT]` in hex, `path:PATH` or `phys:PHYS`
+    #[arg(long = "include-device", value_name = "PATTERN")]
+    include_devices: Vec<DevicePattern>,
+    /// Don't capture input devices matching this, see `--include-device`
+    #[arg(long = "exclude-device", value_name = "PATTERN")]
+    exclude_devices: Vec<DevicePattern>,
+    /// Never capture input devices matching this, added to the `deny` list
+    /// of the config file rather than replacing it
+    #[arg(long = "deny-device", value_name = "PATTERN")]
+    deny_devices: Vec<DevicePattern>,
+    /// Also accept clients that connect without encryption
+    #[arg(long)]
+    allow_plaintext: bool,
+    /// How often clients are pinged, defaults to 1
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    heartbeat_interval: Option<Duration>,
+    /// Drop a client after this many seconds without an answer to a ping,
+    /// defaults to 5
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    heartbeat_timeout: Option<Duration>,
+    /// XKB layout keys are turned into keysyms with for clients in keysym
+    /// mode, see `client --keyboard-layout`
+    #[arg(long, value_name = "LAYOUT[(VARIANT)]")]
+    keyboard_layout: Option<KeyboardLayout>,
+}
 
 #[derive(Parser, Clone, Debug)]
 enum Command {
-    Client { address: net::Ipv4Addr, port: u16 },
-    Server { port: u16 },
+    Client(ClientArgs),
+    Server(ServerArgs),
+    /// Print the fingerprint of this machine's server certificate
+    Fingerprint,
+    /// Show a one-time code and wait for a client to pair with this server
+    Pair {
+        /// Port to wait on, defaults to 6001 so a running server can keep
+        /// its port
+        port: Option<u16>,
+    },
+    /// Pair this machine with a server running `pair`
+    PairWith {
+        address: Option<net::Ipv4Addr>,
+        /// Port `pair` waits on, defaults to 6001
+        port: Option<u16>,
+        /// Name the server knows this machine by, defaults to the hostname
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// List, rename or revoke paired machines
+    KnownHosts {
+        #[command(subcommand)]
+        command: peers::KnownHostsCommand,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -24,6 +155,10 @@ struct Args {
     command: Command,
     #[arg(short, long, required=false, action=clap::ArgAction::SetTrue)]
     verbose: bool,
+    /// Config file to use instead of config.toml in the config directory.
+    /// Command line flags override its settings
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
 }
 
 fn main() {
@@ -36,134 +171,407 @@ fn main() {
     })
     .unwrap();
 
-    match args.command {
-        Command::Client { address, port } => run_client(address, port),
-        Command::Server { port } => run_server(port),
-    }
-}
+    let config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Invalid config file: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-fn connect_to_server(address: net::Ipv4Addr, port: u16) -> net::TcpStream {
-    let socket = SocketAddr::new(IpAddr::V4(address), port);
-    loop {
-        log::info!("Trying to connect");
-        match net::TcpStream::connect_timeout(&socket, Duration::from_secs(2)) {
-            Ok(stream) => {
-                log::info!("Connected to server");
-                return stream;
-            }
+    match args.command {
+        Command::Client(client) => match client_options(client, config.client) {
+            Ok(options) => client::run_client(options),
             Err(e) => {
-                log::info!("Could not connect ({}) Retrying...", e);
-                thread::sleep(Duration::from_secs(1));
+                log::error!("{}", e);
+                std::process::exit(1);
             }
+        },
+        Command::Server(server) => {
+            let options = match server_options(&server, config.server) {
+                Ok(options) => options,
+                Err(e) => {
+                    log::error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            let config_path = args.config;
+            server::run_server(options, move || {
+                let config = Config::load(config_path.as_deref()).map_err(|e| e.to_string())?;
+                server_options(&server, config.server)
+            })
+        }
+        Command::Fingerprint => {
+            let paths = config.server.tls.identity(server::IDENTITY_NAME);
+            match tls::Identity::load_or_generate(&paths) {
+                Ok(identity) => println!("{}", identity.fingerprint()),
+                Err(e) => {
+                    log::error!("Could not load the server certificate: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Pair { port } => pairing::run_pairing_server(
+            config.server.bind.unwrap_or(UNSPECIFIED),
+            port.unwrap_or(pairing::DEFAULT_PORT),
+            &config.server.tls.identity(server::IDENTITY_NAME),
+        ),
+        Command::PairWith {
+            address,
+            port,
+            name,
+        } => {
+            let Some(address) = address.or(config.client.address) else {
+                log::error!("{}", NO_SERVER_ADDRESS);
+                std::process::exit(1);
+            };
+            pairing::run_pairing_client(
+                address,
+                port.unwrap_or(pairing::DEFAULT_PORT),
+                &name.or(config.client.name).unwrap_or_else(hostname),
+                &config.client.tls.identity(client::IDENTITY_NAME),
+            )
         }
+        Command::KnownHosts { command } => peers::run_known_hosts(command),
     }
 }
 
-fn run_client(address: net::Ipv4Addr, port: u16) {
-    let mut injector = input_injection::InputInjector::new();
-    let mut stream: Option<net::TcpStream> = None;
+const UNSPECIFIED: net::IpAddr = net::IpAddr::V4(net::Ipv4Addr::UNSPECIFIED);
 
-    loop {
-        let s = match stream.as_mut() {
-            Some(s) => s,
-            None => {
-                stream = Some(connect_to_server(address, port));
-                stream.as_mut().unwrap()
-            }
-        };
+const NO_SERVER_ADDRESS: &str =
+    "No server address, give one on the command line or as `address` under [client] in the config file";
 
-        let mut buffer = [0; 4];
-        match s.read_exact(&mut buffer) {
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("Error reading from TcpStream: {}", e);
-                stream = None;
-                log::error!("Connection closed");
-                injector.release_all();
-            }
-        }
+/// The client settings, from the command line or else the config file
+fn client_options(args: ClientArgs, file: ClientConfig) -> Result<client::ClientOptions, String> {
+    let address = args
+        .address
+        .or(file.address)
+        .ok_or_else(|| NO_SERVER_ADDRESS.to_owned())?;
+    let plaintext = args.plaintext || file.plaintext.unwrap_or(false);
+    let fingerprint = args.fingerprint.or(file.fingerprint);
+    if plaintext && fingerprint.is_some() {
+        return Err("A fingerprint can't be checked over a plaintext connection".to_owned());
+    }
 
-        let event = event::KeyEvent::from_bytes(buffer);
-        injector.emit(event);
+    Ok(client::ClientOptions {
+        address,
+        port: args.port.or(file.port).unwrap_or(config::DEFAULT_PORT),
+        name: args.name.or(file.name).unwrap_or_else(hostname),
+        fingerprint,
+        plaintext,
+        heartbeat_timeout: args
+            .heartbeat_timeout
+            .or(file.heartbeat_timeout)
+            .unwrap_or(Duration::from_secs(5)),
+        stuck_key_timeout: args
+            .stuck_key_timeout
+            .or(file.stuck_key_timeout)
+            .unwrap_or(Duration::from_secs(2)),
+        key_repeat: args
+            .key_repeat
+            .or(file.key_repeat)
+            .unwrap_or(client::RepeatPolicy::Forward),
+        key_mode: args
+            .key_mode
+            .or(file.key_mode)
+            .unwrap_or(client::KeyMode::Scancode),
+        keyboard_layout: args.keyboard_layout.or(file.keyboard_layout),
+        identity: file.tls.identity(client::IDENTITY_NAME),
+    })
+}
+
+/// A list from the command line, or else the one from the config file
+/// followed by the `[server.clients]` entries
+fn pick<T: Clone>(cli: &[T], listed: Vec<T>, per_client: Vec<T>) -> Vec<T> {
+    if cli.is_empty() {
+        [listed, per_client].concat()
+    } else {
+        cli.to_vec()
     }
 }
 
-fn wait_for_client(port: u16) -> TcpStream {
-    // TODO: Maybe handle these unwraps gracefully
-    let listener = net::TcpListener::bind(("0.0.0.0", port)).unwrap();
-    let (client, addr) = listener.accept().unwrap();
+/// The server settings, from the command line or else the config file. A
+/// list given on the command line replaces the one in the file, along with
+/// that part of the `[server.clients]` entries.
+fn server_options(args: &ServerArgs, file: ServerConfig) -> Result<server::ServerOptions, String> {
+    let clients = file.client_entries()?;
+    let screens = pick(&args.screens, file.screens, clients.screens);
+    let neighbors = pick(&args.neighbors, file.neighbors, clients.neighbors);
+    let layout =
+        Layout::new(&screens, &neighbors).map_err(|e| format!("Invalid screen layout: {}", e))?;
+
+    let mut hotkeys = pick(&args.hotkeys, file.hotkeys, clients.hotkeys);
+    if hotkeys.is_empty() {
+        hotkeys.push(hotkey::DEFAULT_HOTKEY.parse().unwrap());
+    }
+
+    let heartbeat = server::Heartbeat {
+        interval: args
+            .heartbeat_interval
+            .or(file.heartbeat_interval)
+            .unwrap_or(Duration::from_secs(1)),
+        timeout: args
+            .heartbeat_timeout
+            .or(file.heartbeat_timeout)
+            .unwrap_or(Duration::from_secs(5)),
+    };
+    if heartbeat.timeout <= heartbeat.interval {
+        return Err("The heartbeat timeout must be longer than the interval".to_owned());
+    }
+
+    let mut devices = file.devices;
+    if !args.include_devices.is_empty() {
+        devices.include = args.include_devices.clone();
+    }
+    if !args.exclude_devices.is_empty() {
+        devices.exclude = args.exclude_devices.clone();
+    }
+    devices.deny.extend(args.deny_devices.iter().cloned());
 
-    log::info!("client connected from {}", addr);
-    client
+    Ok(server::ServerOptions {
+        bind: args.bind.or(file.bind).unwrap_or(UNSPECIFIED),
+        port: args.port.or(file.port).unwrap_or(config::DEFAULT_PORT),
+        layout,
+        hotkeys,
+        allow_plaintext: args.allow_plaintext || file.allow_plaintext.unwrap_or(false),
+        heartbeat,
+        devices,
+        keyboard_layout: args.keyboard_layout.clone().or(file.keyboard_layout),
+        identity: file.tls.identity(server::IDENTITY_NAME),
+    })
 }
 
-fn run_server(port: u16) {
-    let (sender, receiver) = mpsc::channel::<Event>();
-
-    let mut sending = false;
-    input_capture::init(move |e| match e {
-        Event::Key(_) => {
-            if sending {
-                sender.send(e).unwrap();
-                true
-            } else {
-                false
-            }
-        }
-        Event::Hotkey => {
-            sending = !sending;
-            log::info!("Turned {} sending", if sending { "On" } else { "Off" });
-
-            // Focus was brought back from the client, make sure it gets the hotkey release
-            if !sending {
-                let release = |hid| {
-                    sender
-                        .send(Event::Key(KeyEvent {
-                            hid,
-                            kind: KeyEventKind::Release,
-                            mods: Modifiers::empty(),
-                        }))
-                        .unwrap();
-                };
-
-                release(0xE0);
-                release(0xE4);
-                release(0xE2);
-                release(0xE6);
-            }
+/// Where certificates and other per-machine state are kept
+fn config_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("lankm")
+}
 
-            true
-        }
-    });
-
-    let mut maybe_client = None;
-
-    loop {
-        let client = match maybe_client.as_mut() {
-            Some(c) => c,
-            None => {
-                maybe_client = Some(wait_for_client(port));
-
-                // clear anything that was buffered before a client connected
-                loop {
-                    match receiver.try_recv() {
-                        Ok(_) => {}
-                        Err(TryRecvError::Empty) => break,
-                        Err(e) => panic!("Channel read error: {}", e),
-                    }
-                }
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let secs = s
+        .parse::<f64>()
+        .map_err(|_| format!("'{}' is not a number of seconds", s))?;
+    seconds(secs).map_err(|e| format!("'{}': {}", s, e))
+}
 
-                maybe_client.as_mut().unwrap()
-            }
+/// A positive and finite number of seconds, small enough for a `Duration`
+fn seconds(secs: f64) -> Result<Duration, String> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if secs > 0.0 => Ok(duration),
+        Ok(_) => Err("the number of seconds must be positive".to_owned()),
+        Err(_) => Err("too many seconds".to_owned()),
+    }
+}
+
+fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Command {
+        Args::try_parse_from(["lankm-headless"].iter().chain(args))
+            .unwrap()
+            .command
+    }
+
+    fn server(args: &[&str], file: &str) -> Result<server::ServerOptions, String> {
+        let Command::Server(args) = command(&[&["server"], args].concat()) else {
+            unreachable!();
         };
+        server_options(&args, toml::from_str::<Config>(file).unwrap().server)
+    }
 
-        let event = receiver.recv().unwrap();
-        match client.write_all(&event.to_bytes()) {
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("Error writing to client: {}", e);
-                maybe_client = None;
-            }
+    fn client(args: &[&str], file: &str) -> Result<client::ClientOptions, String> {
+        let Command::Client(args) = command(&[&["client"], args].concat()) else {
+            unreachable!();
+        };
+        client_options(args, toml::from_str::<Config>(file).unwrap().client)
+    }
+
+    fn actions(options: &server::ServerOptions) -> Vec<String> {
+        options
+            .hotkeys
+            .iter()
+            .map(|b| format!("{:?}", b.action))
+            .collect()
+    }
+
+    fn patterns(patterns: &[DevicePattern]) -> Vec<String> {
+        patterns.iter().map(|p| format!("{:?}", p)).collect()
+    }
+
+    const SERVER_FILE: &str = r#"
+        [server]
+        port = 7000
+        bind = "127.0.0.1"
+        hotkeys = ["ctrl+alt+0=local"]
+        neighbors = ["laptop:left:server"]
+        heartbeat-timeout = 3
+        keyboard-layout = "fr"
+
+        [server.devices]
+        include = ["Keychron"]
+        deny = ["Yubico"]
+
+        [server.clients.desktop]
+        position = "right:server"
+        hotkey = "ctrl+alt+2"
+    "#;
+
+    #[test]
+    fn server_defaults() {
+        let options = server(&[], "").unwrap();
+        assert_eq!(options.port, config::DEFAULT_PORT);
+        assert_eq!(options.bind, UNSPECIFIED);
+        assert_eq!(actions(&options), ["Next"]);
+        assert_eq!(options.heartbeat.interval, Duration::from_secs(1));
+        assert_eq!(options.heartbeat.timeout, Duration::from_secs(5));
+        assert!(!options.allow_plaintext);
+        assert_eq!(options.keyboard_layout, None);
+    }
+
+    #[test]
+    fn server_file_settings() {
+        let options = server(&[], SERVER_FILE).unwrap();
+        assert_eq!(options.port, 7000);
+        assert_eq!(options.bind.to_string(), "127.0.0.1");
+        // The listed hotkeys, then the ones of [server.clients]
+        assert_eq!(actions(&options), ["Local", "Client(\"desktop\")"]);
+        assert_eq!(options.heartbeat.timeout, Duration::from_secs(3));
+        assert_eq!(options.keyboard_layout, "fr".parse().ok());
+        assert_eq!(patterns(&options.devices.include), ["Name(\"Keychron\")"]);
+    }
+
+    #[test]
+    fn server_flags_win() {
+        let options = server(
+            &[
+                "8000",
+                "--hotkey",
+                "ctrl+alt+9=next",
+                "--keyboard-layout",
+                "de(nodeadkeys)",
+                "--include-device",
+                "id:046d",
+                "--deny-device",
+                "Scanner",
+                "--allow-plaintext",
+            ],
+            SERVER_FILE,
+        )
+        .unwrap();
+        assert_eq!(options.port, 8000);
+        assert_eq!(options.bind.to_string(), "127.0.0.1");
+        // Replaces the file's hotkeys along with those of [server.clients]
+        assert_eq!(actions(&options), ["Next"]);
+        assert_eq!(options.keyboard_layout, "de(nodeadkeys)".parse().ok());
+        assert_eq!(
+            patterns(&options.devices.include),
+            ["Id { vendor: 1133, product: None }"]
+        );
+        // The denylist grows instead
+        assert_eq!(
+            patterns(&options.devices.deny),
+            ["Name(\"Yubico\")", "Name(\"Scanner\")"]
+        );
+        assert!(options.allow_plaintext);
+    }
+
+    #[test]
+    fn server_flags_replace_layout() {
+        let file = r#"
+            [server]
+            neighbors = ["desktop:left:server"]
+
+            [server.clients.laptop]
+            position = "left:server"
+        "#;
+
+        // The file places two screens left of the server, the flag
+        // replaces both placements
+        assert!(server(&[], file).is_err());
+        assert!(server(&["--neighbor", "laptop:left:server"], file).is_ok());
+    }
+
+    #[test]
+    fn server_rejects_short_heartbeat_timeout() {
+        assert!(server(&["--heartbeat-interval", "5"], "").is_err());
+        assert!(server(&["--heartbeat-interval", "2"], SERVER_FILE).is_ok());
+        assert!(server(&["--heartbeat-interval", "3"], SERVER_FILE).is_err());
+    }
+
+    #[test]
+    fn client_settings() {
+        let file = r#"
+            [client]
+            address = "192.168.1.10"
+            port = 7000
+            name = "laptop"
+            key-repeat = "500:25"
+            key-mode = "keysym"
+            keyboard-layout = "fr"
+        "#;
+
+        let options = client(&[], file).unwrap();
+        assert_eq!(options.address.to_string(), "192.168.1.10");
+        assert_eq!(options.port, 7000);
+        assert_eq!(options.name, "laptop");
+        assert_eq!(options.key_repeat, "500:25".parse().unwrap());
+        assert_eq!(options.key_mode, client::KeyMode::Keysym);
+        assert_eq!(options.keyboard_layout, "fr".parse().ok());
+        assert_eq!(options.stuck_key_timeout, Duration::from_secs(2));
+
+        let options = client(
+            &[
+                "10.0.0.2",
+                "--name",
+                "pc",
+                "--key-repeat",
+                "off",
+                "--keyboard-layout",
+                "us",
+            ],
+            file,
+        )
+        .unwrap();
+        assert_eq!(options.address.to_string(), "10.0.0.2");
+        assert_eq!(options.port, 7000);
+        assert_eq!(options.name, "pc");
+        assert_eq!(options.key_repeat, client::RepeatPolicy::Off);
+        assert_eq!(options.keyboard_layout, "us".parse().ok());
+    }
+
+    #[test]
+    fn client_needs_an_address() {
+        assert_eq!(client(&[], "").err().as_deref(), Some(NO_SERVER_ADDRESS));
+
+        let options = client(&["10.0.0.2"], "").unwrap();
+        assert_eq!(options.port, config::DEFAULT_PORT);
+        assert_eq!(options.key_repeat, client::RepeatPolicy::Forward);
+        assert_eq!(options.key_mode, client::KeyMode::Scancode);
+    }
+
+    #[test]
+    fn client_rejects_fingerprint_over_plaintext() {
+        let file = "[client]\nplaintext = true";
+        assert!(client(&["10.0.0.2", "--fingerprint", "aa"], file).is_err());
+        assert!(client(&["10.0.0.2"], file).unwrap().plaintext);
+    }
+
+    #[test]
+    fn parses_seconds() {
+        assert_eq!(parse_seconds("1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_seconds("5"), Ok(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn rejects_bad_seconds() {
+        for s in ["0", "-1", "abc", "", "inf", "NaN", "1e30"] {
+            assert!(parse_seconds(s).is_err(), "{} was accepted", s);
         }
     }
 }
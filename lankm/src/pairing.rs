@@ -0,0 +1,535 @@
+// Pairing and authentication
+//
+// Pairing runs SPAKE2 over ristretto255: both sides mix the short code into
+// a Diffie-Hellman exchange, so they only end up with the same secret if
+// they typed the same code, and someone listening in can't brute force the
+// code offline. The secret is bound to both names and to the server's
+// certificate, and each side confirms it before a long-term key is derived
+// from it and stored.
+//
+// Every later connection then runs a challenge/response with that key, bound
+// to the TLS session so it can't be relayed to another connection.
+
+use std::io::{self, Write};
+use std::net::{self, IpAddr, SocketAddr};
+use std::time::Duration;
+
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::{constants, Scalar};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+
+use crate::event::{self, Capabilities, HandshakeError, Message};
+use crate::layout;
+use crate::peers::{Peer, PeerStore};
+use crate::tls::{self, Identity, IdentityPaths, Stream};
+
+/// Port `pair` waits on unless told otherwise, not the server's so pairing
+/// works while the server runs
+pub const DEFAULT_PORT: u16 = 6001;
+
+/// How long each side gets to answer during pairing
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(30);
+
+const CODE_DIGITS: usize = 6;
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    // TODO: Maybe handle this unwrap gracefully
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    bytes
+}
+
+/// A fresh code to show the user, e.g. `123-456`
+fn generate_code() -> String {
+    let digits: String = random_bytes::<CODE_DIGITS>()
+        .iter()
+        // Slightly biased, which doesn't matter for a one-shot code
+        .map(|b| char::from(b'0' + b % 10))
+        .collect();
+    format!("{}-{}", &digits[..3], &digits[3..])
+}
+
+/// Codes are compared without the dash or any spaces the user typed
+fn normalize_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+fn hash_to_point(label: &[u8]) -> RistrettoPoint {
+    let hash = digest::digest(&digest::SHA512, label);
+    RistrettoPoint::from_uniform_bytes(hash.as_ref().try_into().unwrap())
+}
+
+fn hash_to_scalar(bytes: &[u8]) -> Scalar {
+    let hash = digest::digest(&digest::SHA512, bytes);
+    Scalar::from_bytes_mod_order_wide(hash.as_ref().try_into().unwrap())
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Role {
+    Client,
+    Server,
+}
+
+/// One side of a SPAKE2 exchange
+struct Spake2 {
+    role: Role,
+    secret: Scalar,
+    password: Scalar,
+    /// Our public share, blinded with the password
+    share: [u8; 32],
+}
+
+impl Spake2 {
+    fn new(role: Role, code: &str) -> Self {
+        let secret = Scalar::from_bytes_mod_order_wide(&random_bytes());
+        let password = hash_to_scalar(normalize_code(code).as_bytes());
+
+        let blind = match role {
+            Role::Client => hash_to_point(b"lankm spake2 M"),
+            Role::Server => hash_to_point(b"lankm spake2 N"),
+        };
+        let share = (constants::RISTRETTO_BASEPOINT_POINT * secret + blind * password)
+            .compress()
+            .to_bytes();
+
+        Self {
+            role,
+            secret,
+            password,
+            share,
+        }
+    }
+
+    /// Combines the peer's share with ours into a transcript hash, which is
+    /// only the same on both sides if both used the same code. The client's
+    /// and then the server's certificate fingerprint are bound to it.
+    fn finish(
+        &self,
+        peer_share: &[u8; 32],
+        client_name: &str,
+        server_name: &str,
+        fingerprints: [&str; 2],
+    ) -> io::Result<[u8; 32]> {
+        let peer_point = CompressedRistretto(*peer_share)
+            .decompress()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid pairing share"))?;
+
+        let (peer_blind, client_share, server_share) = match self.role {
+            Role::Client => (hash_to_point(b"lankm spake2 N"), &self.share, peer_share),
+            Role::Server => (hash_to_point(b"lankm spake2 M"), peer_share, &self.share),
+        };
+        let shared = ((peer_point - peer_blind * self.password) * self.secret).compress();
+
+        let mut transcript = digest::Context::new(&digest::SHA256);
+        for part in [
+            client_name.as_bytes(),
+            server_name.as_bytes(),
+            fingerprints[0].as_bytes(),
+            fingerprints[1].as_bytes(),
+            client_share,
+            server_share,
+            shared.as_bytes(),
+            self.password.as_bytes(),
+        ] {
+            transcript.update(&(part.len() as u64).to_le_bytes());
+            transcript.update(part);
+        }
+
+        Ok(transcript.finish().as_ref().try_into().unwrap())
+    }
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut context = hmac::Context::with_key(&hmac::Key::new(hmac::HMAC_SHA256, key));
+    for part in parts {
+        context.update(part);
+    }
+    context.sign().as_ref().try_into().unwrap()
+}
+
+fn verify_mac(key: &[u8], parts: &[&[u8]], tag: &[u8; 32]) -> bool {
+    // ring compares in constant time
+    hmac::verify(
+        &hmac::Key::new(hmac::HMAC_SHA256, key),
+        &parts.concat(),
+        tag,
+    )
+    .is_ok()
+}
+
+fn expect_pake(stream: &mut Stream) -> Result<[u8; 32], HandshakeError> {
+    match Message::read_from(stream)? {
+        Message::Pake(share) => Ok(share),
+        Message::Reject(reason) => Err(HandshakeError::Rejected(reason)),
+        m => Err(unexpected("Pake", m)),
+    }
+}
+
+fn expect_challenge(stream: &mut Stream) -> Result<[u8; 32], HandshakeError> {
+    match Message::read_from(stream)? {
+        Message::Challenge(nonce) => Ok(nonce),
+        Message::Reject(reason) => Err(HandshakeError::Rejected(reason)),
+        m => Err(unexpected("Challenge", m)),
+    }
+}
+
+fn expect_proof(stream: &mut Stream) -> Result<[u8; 32], HandshakeError> {
+    match Message::read_from(stream)? {
+        Message::Proof(proof) => Ok(proof),
+        Message::Reject(reason) => Err(HandshakeError::Rejected(reason)),
+        m => Err(unexpected("Proof", m)),
+    }
+}
+
+fn unexpected(expected: &str, message: Message) -> HandshakeError {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("expected {}, got {:?}", expected, message),
+    )
+    .into()
+}
+
+/// Proves to the server that we hold the key from pairing, and checks that
+/// it does too
+pub fn client_authenticate(stream: &mut Stream, key: &[u8; 32]) -> Result<(), HandshakeError> {
+    let binding = stream.channel_binding();
+    let client_nonce = random_bytes();
+    Message::Challenge(client_nonce).write_to(stream)?;
+    let server_nonce = expect_challenge(stream)?;
+
+    let nonces: [&[u8]; 3] = [&client_nonce, &server_nonce, &binding];
+    Message::Proof(mac(key, &[b"client", nonces[0], nonces[1], nonces[2]])).write_to(stream)?;
+
+    let server_proof = expect_proof(stream)?;
+    if !verify_mac(
+        key,
+        &[b"server", nonces[0], nonces[1], nonces[2]],
+        &server_proof,
+    ) {
+        return Err(HandshakeError::AuthFailed);
+    }
+
+    Ok(())
+}
+
+/// Server side of `client_authenticate`. The client proves itself first, a
+/// client that fails gets a Reject and learns nothing.
+pub fn server_authenticate(stream: &mut Stream, key: &[u8; 32]) -> Result<(), HandshakeError> {
+    let binding = stream.channel_binding();
+    let client_nonce = expect_challenge(stream)?;
+    let server_nonce = random_bytes();
+    Message::Challenge(server_nonce).write_to(stream)?;
+
+    let nonces: [&[u8]; 3] = [&client_nonce, &server_nonce, &binding];
+    let client_proof = expect_proof(stream)?;
+    if !verify_mac(
+        key,
+        &[b"client", nonces[0], nonces[1], nonces[2]],
+        &client_proof,
+    ) {
+        Message::Reject("authentication failed, try pairing again".to_owned()).write_to(stream)?;
+        return Err(HandshakeError::AuthFailed);
+    }
+
+    Message::Proof(mac(key, &[b"server", nonces[0], nonces[1], nonces[2]])).write_to(stream)?;
+
+    Ok(())
+}
+
+/// Runs the key exchange after the Hello/HelloAck, returns the long-term key
+fn exchange(
+    stream: &mut Stream,
+    role: Role,
+    code: &str,
+    client_name: &str,
+    server_name: &str,
+    fingerprints: [&str; 2],
+) -> Result<[u8; 32], HandshakeError> {
+    let spake = Spake2::new(role, code);
+    Message::Pake(spake.share).write_to(stream)?;
+    let peer_share = expect_pake(stream)?;
+    let transcript = spake.finish(&peer_share, client_name, server_name, fingerprints)?;
+
+    // The client confirms first, so the server can tell it the code was
+    // wrong instead of just hanging up. Seeing a confirmation doesn't help
+    // an attacker guess the code offline.
+    match role {
+        Role::Client => {
+            Message::Proof(mac(&transcript, &[b"client confirm"])).write_to(stream)?;
+            if !verify_mac(&transcript, &[b"server confirm"], &expect_proof(stream)?) {
+                return Err(HandshakeError::AuthFailed);
+            }
+        }
+        Role::Server => {
+            if !verify_mac(&transcript, &[b"client confirm"], &expect_proof(stream)?) {
+                return Err(HandshakeError::AuthFailed);
+            }
+            Message::Proof(mac(&transcript, &[b"server confirm"])).write_to(stream)?;
+        }
+    }
+
+    Ok(mac(&transcript, &[b"long-term key"]))
+}
+
+fn fail(message: &str, e: impl std::fmt::Display) -> ! {
+    log::error!("{}: {}", message, e);
+    std::process::exit(1);
+}
+
+/// `lankm-headless pair` on the server: shows a code and waits for a single
+/// client to pair with it
+pub fn run_pairing_server(bind: IpAddr, port: u16, identity: &IdentityPaths) {
+    let identity = match Identity::load_or_generate(identity) {
+        Ok(identity) => identity,
+        Err(e) => fail("Could not load the server certificate", e),
+    };
+    let config = tls::server_config(&identity);
+
+    let listener = match net::TcpListener::bind((bind, port)) {
+        Ok(listener) => listener,
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => fail(
+            &format!("Port {} is taken, pick another one with `pair PORT`", port),
+            e,
+        ),
+        Err(e) => fail(&format!("Could not listen on port {}", port), e),
+    };
+
+    let code = generate_code();
+    println!("Pairing code: {}", code);
+    println!(
+        "Run `lankm-headless pair-with <address> {}` on the client and enter it",
+        port
+    );
+
+    // Only one attempt per code, so guessing it isn't an option
+    let (tcp, addr) = match listener.accept() {
+        Ok(accepted) => accepted,
+        Err(e) => fail("Error accepting a client", e),
+    };
+    let mut stream = match tcp
+        .set_read_timeout(Some(PAIRING_TIMEOUT))
+        .and_then(|_| tls::accept(tcp, &config, false))
+    {
+        Ok(stream) => stream,
+        Err(e) => fail("Could not set up encryption", e),
+    };
+    // Encrypted connections always come with a client certificate
+    let client_fingerprint = stream.peer_fingerprint().unwrap();
+
+    let server_name = crate::hostname();
+    let hello = match event::server_handshake(&mut stream, &server_name, |hello| {
+        if hello.name.is_empty() || hello.name.contains('\n') {
+            return Err("invalid client name".to_owned());
+        }
+        layout::check_client_name(&hello.name)
+            .map_err(|e| format!("{}, pair again with `--name`", e))
+    }) {
+        Ok(hello) => hello,
+        Err(e) => fail("Handshake failed", e),
+    };
+
+    let key = match exchange(
+        &mut stream,
+        Role::Server,
+        &code,
+        &hello.name,
+        &server_name,
+        [&client_fingerprint, &identity.fingerprint()],
+    ) {
+        Ok(key) => key,
+        Err(HandshakeError::AuthFailed) => {
+            let _ = Message::Reject("wrong pairing code".to_owned()).write_to(&mut stream);
+            fail(
+                "Pairing failed",
+                format!("{} ({}) entered the wrong code", hello.name, addr),
+            )
+        }
+        Err(e) => fail("Pairing failed", e),
+    };
+
+    let stored = PeerStore::load(crate::peers::CLIENTS_FILE).and_then(|mut store| {
+        store.insert(Peer {
+            name: hello.name.clone(),
+            key,
+            fingerprint: Some(client_fingerprint),
+        });
+        store.save()
+    });
+    if let Err(e) = stored {
+        fail("Could not store the pairing", e);
+    }
+
+    println!("Paired with {} ({})", hello.name, addr);
+}
+
+/// `lankm-headless pair-with` on the client: asks for the code the server
+/// shows and pairs with it
+pub fn run_pairing_client(address: net::Ipv4Addr, port: u16, name: &str, identity: &IdentityPaths) {
+    print!("Pairing code: ");
+    let _ = io::stdout().flush();
+    let mut code = String::new();
+    if let Err(e) = io::stdin().read_line(&mut code) {
+        fail("Could not read the pairing code", e);
+    }
+
+    let identity = match Identity::load_or_generate(identity) {
+        Ok(identity) => identity,
+        Err(e) => fail("Could not load the client certificate", e),
+    };
+
+    let address = IpAddr::V4(address);
+    let connected =
+        net::TcpStream::connect_timeout(&SocketAddr::new(address, port), Duration::from_secs(2))
+            .and_then(|tcp| {
+                tcp.set_read_timeout(Some(PAIRING_TIMEOUT))?;
+                tls::connect(tcp, address, Some(&tls::client_config(&identity, None)))
+            });
+    let mut stream = match connected {
+        Ok(stream) => stream,
+        Err(e) => fail("Could not connect", e),
+    };
+    // Not trusted yet, the key exchange is bound to it so it can be
+    // trusted afterwards
+    let fingerprint = stream.peer_fingerprint().unwrap();
+
+    // Nothing but the key exchange happens on this connection
+    let ack = match event::client_handshake(&mut stream, name, Capabilities::empty()) {
+        Ok(ack) => ack,
+        Err(e) => fail("Handshake failed", e),
+    };
+
+    let key = match exchange(
+        &mut stream,
+        Role::Client,
+        &code,
+        name,
+        &ack.name,
+        [&identity.fingerprint(), &fingerprint],
+    ) {
+        Ok(key) => key,
+        Err(e) => fail("Pairing failed", e),
+    };
+
+    let stored = PeerStore::load(crate::peers::SERVERS_FILE).and_then(|mut store| {
+        store.insert(Peer {
+            name: ack.name.clone(),
+            key,
+            fingerprint: Some(fingerprint),
+        });
+        store.save()
+    });
+    if let Err(e) = stored {
+        fail("Could not store the pairing", e);
+    }
+
+    println!("Paired with server {}", ack.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINTS: [&str; 2] = ["client fingerprint", "server fingerprint"];
+
+    /// Runs both sides of the exchange, returning the client's and the
+    /// server's transcript
+    fn pair(client_code: &str, server_code: &str) -> ([u8; 32], [u8; 32]) {
+        let client = Spake2::new(Role::Client, client_code);
+        let server = Spake2::new(Role::Server, server_code);
+        let client_transcript = client
+            .finish(&server.share, "laptop", "desk", FINGERPRINTS)
+            .unwrap();
+        let server_transcript = server
+            .finish(&client.share, "laptop", "desk", FINGERPRINTS)
+            .unwrap();
+        (client_transcript, server_transcript)
+    }
+
+    #[test]
+    fn same_code_gives_same_key() {
+        let (client, server) = pair("123-456", "123456");
+        assert_eq!(client, server);
+        assert!(verify_mac(
+            &server,
+            &[b"client confirm"],
+            &mac(&client, &[b"client confirm"])
+        ));
+    }
+
+    #[test]
+    fn each_pairing_gets_a_new_key() {
+        assert_ne!(pair("123-456", "123-456").0, pair("123-456", "123-456").0);
+    }
+
+    #[test]
+    fn wrong_code_fails_confirmation() {
+        let (client, server) = pair("123-456", "123-457");
+        assert_ne!(client, server);
+        assert!(!verify_mac(
+            &server,
+            &[b"client confirm"],
+            &mac(&client, &[b"client confirm"])
+        ));
+    }
+
+    #[test]
+    fn tampered_share_gives_different_keys() {
+        let client = Spake2::new(Role::Client, "123-456");
+        let server = Spake2::new(Role::Server, "123-456");
+        let server_transcript = server
+            .finish(&client.share, "laptop", "desk", FINGERPRINTS)
+            .unwrap();
+
+        // A share from someone else who knows the code, as a relay would send
+        let attacker = Spake2::new(Role::Server, "123-456");
+        let client_transcript = client
+            .finish(&attacker.share, "laptop", "desk", FINGERPRINTS)
+            .unwrap();
+        assert_ne!(client_transcript, server_transcript);
+
+        // A flipped bit either isn't a point at all or gives another key
+        let mut flipped = server.share;
+        flipped[0] ^= 1;
+        if let Ok(transcript) = client.finish(&flipped, "laptop", "desk", FINGERPRINTS) {
+            assert_ne!(transcript, server_transcript);
+        }
+    }
+
+    #[test]
+    fn transcript_binds_names_and_certificates() {
+        let client = Spake2::new(Role::Client, "123-456");
+        let server = Spake2::new(Role::Server, "123-456");
+        let expected = server
+            .finish(&client.share, "laptop", "desk", FINGERPRINTS)
+            .unwrap();
+
+        let tampered = [
+            client.finish(&server.share, "laptop2", "desk", FINGERPRINTS),
+            client.finish(&server.share, "laptop", "desk2", FINGERPRINTS),
+            client.finish(&server.share, "laptop", "desk", ["other", FINGERPRINTS[1]]),
+            client.finish(&server.share, "laptop", "desk", [FINGERPRINTS[0], "other"]),
+            // Moving bytes between names must not give the same transcript
+            client.finish(&server.share, "laptopd", "esk", FINGERPRINTS),
+        ];
+        for transcript in tampered {
+            assert_ne!(transcript.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn invalid_share_is_an_error() {
+        let client = Spake2::new(Role::Client, "123-456");
+        assert!(client
+            .finish(&[0xff; 32], "laptop", "desk", FINGERPRINTS)
+            .is_err());
+    }
+
+    #[test]
+    fn codes() {
+        let code = generate_code();
+        assert_eq!(code.len(), CODE_DIGITS + 1);
+        assert_eq!(normalize_code(&code).len(), CODE_DIGITS);
+        assert_eq!(normalize_code(" 123 - 456\n"), "123456");
+    }
+}
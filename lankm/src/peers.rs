@@ -0,0 +1,396 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::event::HandshakeError;
+
+/// File the server keeps its paired clients in
+pub const CLIENTS_FILE: &str = "paired_clients";
+/// File a client keeps its paired servers in
+pub const SERVERS_FILE: &str = "paired_servers";
+
+// The known hosts: every machine we paired with, identified by its
+// certificate like SSH does with host keys.
+
+/// A machine we paired with
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub name: String,
+    /// Long-term key agreed on while pairing
+    pub key: [u8; 32],
+    /// Fingerprint of the peer's certificate, if it has one
+    pub fingerprint: Option<String>,
+}
+
+impl Peer {
+    // One peer per line: `KEY FINGERPRINT NAME`, hex encoded, with `-` for
+    // a missing fingerprint. The name goes last since it may contain spaces.
+    fn to_line(&self) -> String {
+        format!(
+            "{} {} {}",
+            to_hex(&self.key),
+            self.fingerprint.as_deref().unwrap_or("-"),
+            self.name
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut parts = line.splitn(3, ' ');
+        let key = from_hex(parts.next()?)?.try_into().ok()?;
+        let fingerprint = match parts.next()? {
+            "-" => None,
+            f => Some(f.to_owned()),
+        };
+        let name = parts.next()?.to_owned();
+
+        Some(Self {
+            name,
+            key,
+            fingerprint,
+        })
+    }
+}
+
+/// The peers stored in one file of the config directory
+pub struct PeerStore {
+    path: PathBuf,
+    peers: Vec<Peer>,
+}
+
+impl PeerStore {
+    pub fn load(file: &str) -> io::Result<Self> {
+        let path = crate::config_dir().join(file);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut peers = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Peer::from_line(line) {
+                Some(peer) => peers.push(peer),
+                None => log::warn!("Ignoring malformed line {} of {}", i + 1, path.display()),
+            }
+        }
+
+        Ok(Self { path, peers })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let contents: String = self.peers.iter().map(|p| p.to_line() + "\n").collect();
+
+        fs::create_dir_all(crate::config_dir())?;
+        crate::tls::write_private(&self.path, contents.as_bytes())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Peer> {
+        self.peers.iter().find(|p| p.name == name)
+    }
+
+    /// Adds a peer, replacing any previous one with the same name or
+    /// certificate
+    pub fn insert(&mut self, peer: Peer) {
+        self.peers.retain(|p| {
+            p.name != peer.name && (p.fingerprint.is_none() || p.fingerprint != peer.fingerprint)
+        });
+        self.peers.push(peer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Works out which known peer connected, by its certificate if it
+    /// presented one and by the name it gave otherwise. A peer seen without
+    /// a certificate before has this one trusted from now on, a peer whose
+    /// certificate changed is refused.
+    pub fn identify(
+        &mut self,
+        name: &str,
+        fingerprint: Option<&str>,
+    ) -> Result<Peer, HandshakeError> {
+        if let Some(peer) = fingerprint.and_then(|f| {
+            self.peers
+                .iter()
+                .find(|p| p.fingerprint.as_deref() == Some(f))
+        }) {
+            return Ok(peer.clone());
+        }
+
+        let Some(peer) = self.peers.iter_mut().find(|p| p.name == name) else {
+            return Err(HandshakeError::NotPaired(name.to_owned()));
+        };
+
+        match (&peer.fingerprint, fingerprint) {
+            (Some(expected), Some(actual)) => {
+                log::error!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                log::error!("@    WARNING: THE IDENTITY OF {} HAS CHANGED!", name);
+                log::error!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+                log::error!("Someone could be impersonating it, or its certificate was");
+                log::error!("regenerated. Expected fingerprint");
+                log::error!("  {}", expected);
+                log::error!("but it presented");
+                log::error!("  {}", actual);
+                log::error!(
+                    "If this is expected, run `lankm-headless known-hosts revoke {}` and pair again",
+                    name
+                );
+
+                Err(HandshakeError::IdentityChanged(name.to_owned()))
+            }
+            (None, Some(actual)) => {
+                log::warn!(
+                    "Trusting the certificate of {} from now on: {}",
+                    name,
+                    actual
+                );
+                peer.fingerprint = Some(actual.to_owned());
+                let peer = peer.clone();
+                if let Err(e) = self.save() {
+                    log::error!("Could not store the certificate of {}: {}", name, e);
+                }
+
+                Ok(peer)
+            }
+            // Plaintext, the key from pairing is all there is to go on
+            (_, None) => Ok(peer.clone()),
+        }
+    }
+
+    /// Renames a peer, returns false if there's no peer with that name
+    pub fn rename(&mut self, name: &str, new_name: &str) -> Result<bool, String> {
+        if self.get(name).is_none() {
+            return Ok(false);
+        }
+        if self.get(new_name).is_some() {
+            return Err(format!("{} is already taken", new_name));
+        }
+
+        for peer in self.peers.iter_mut().filter(|p| p.name == name) {
+            peer.name = new_name.to_owned();
+        }
+        Ok(true)
+    }
+
+    /// Forgets a peer, returns false if there's no peer with that name
+    pub fn revoke(&mut self, name: &str) -> bool {
+        let len = self.peers.len();
+        self.peers.retain(|p| p.name != name);
+        self.peers.len() != len
+    }
+}
+
+#[derive(clap::Subcommand, Clone, Debug)]
+pub enum KnownHostsCommand {
+    /// List the paired clients and servers
+    List,
+    /// Rename a paired machine. Clients are known to the server by this
+    /// name in layouts and hotkeys
+    Rename { name: String, new_name: String },
+    /// Forget a paired machine, it has to pair again to connect
+    Revoke { name: String },
+}
+
+/// `lankm-headless known-hosts`, edits both the clients and servers files
+pub fn run_known_hosts(command: KnownHostsCommand) {
+    let files = [
+        (CLIENTS_FILE, "Clients paired with this server"),
+        (SERVERS_FILE, "Servers this machine is paired with"),
+    ];
+    let mut stores = Vec::new();
+    for (file, title) in files {
+        match PeerStore::load(file) {
+            Ok(store) => stores.push((store, title)),
+            Err(e) => {
+                log::error!("Could not load {}: {}", file, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut changed = false;
+    let name = match &command {
+        KnownHostsCommand::List => {
+            for (store, title) in stores.iter().filter(|(s, _)| !s.is_empty()) {
+                println!("{}:", title);
+                for peer in &store.peers {
+                    let fingerprint = peer
+                        .fingerprint
+                        .as_deref()
+                        .unwrap_or("(no certificate seen yet)");
+                    println!("  {}  {}", peer.name, fingerprint);
+                }
+            }
+            if stores.iter().all(|(s, _)| s.is_empty()) {
+                println!("Not paired with anything yet");
+            }
+            return;
+        }
+        KnownHostsCommand::Rename { name, new_name } => {
+            for (store, _) in &mut stores {
+                match store.rename(name, new_name) {
+                    Ok(renamed) => changed |= renamed,
+                    Err(e) => {
+                        log::error!("Can't rename {}: {}", name, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            name
+        }
+        KnownHostsCommand::Revoke { name } => {
+            for (store, _) in &mut stores {
+                changed |= store.revoke(name);
+            }
+            name
+        }
+    };
+
+    if !changed {
+        log::error!("No paired machine is called {}", name);
+        std::process::exit(1);
+    }
+
+    for (store, _) in &stores {
+        if let Err(e) = store.save() {
+            log::error!("Could not save {}: {}", store.path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn peer(name: &str, key: u8, fingerprint: Option<&str>) -> Peer {
+        Peer {
+            name: name.to_owned(),
+            key: [key; 32],
+            fingerprint: fingerprint.map(str::to_owned),
+        }
+    }
+
+    // A store saving to its own file in the temp directory
+    fn store(test: &str, peers: Vec<Peer>) -> PeerStore {
+        let path = std::env::temp_dir().join(format!("lankm-{}-{}", test, process::id()));
+        PeerStore { path, peers }
+    }
+
+    #[test]
+    fn identifies_by_certificate() {
+        let mut store = store(
+            "by-certificate",
+            vec![
+                peer("laptop", 1, Some("aa")),
+                peer("desktop", 2, Some("bb")),
+            ],
+        );
+
+        // A renamed machine is still known by its certificate
+        let found = store.identify("desktop", Some("aa")).unwrap();
+        assert_eq!(found.name, "laptop");
+        assert_eq!(found.key, [1; 32]);
+
+        assert!(matches!(
+            store.identify("laptop", Some("cc")),
+            Err(HandshakeError::IdentityChanged(name)) if name == "laptop"
+        ));
+        assert!(matches!(
+            store.identify("tablet", Some("cc")),
+            Err(HandshakeError::NotPaired(name)) if name == "tablet"
+        ));
+    }
+
+    #[test]
+    fn identifies_by_name_without_certificate() {
+        let mut store = store("plaintext", vec![peer("laptop", 1, Some("aa"))]);
+
+        assert_eq!(store.identify("laptop", None).unwrap().key, [1; 32]);
+        assert!(matches!(
+            store.identify("desktop", None),
+            Err(HandshakeError::NotPaired(_))
+        ));
+        assert!(!store.path.exists());
+    }
+
+    #[test]
+    fn trusts_first_certificate() {
+        let mut store = store("first-certificate", vec![peer("laptop", 1, None)]);
+
+        let found = store.identify("laptop", Some("aa")).unwrap();
+        assert_eq!(found.fingerprint.as_deref(), Some("aa"));
+        assert_eq!(
+            store.get("laptop").unwrap().fingerprint.as_deref(),
+            Some("aa")
+        );
+
+        let saved = fs::read_to_string(&store.path).unwrap();
+        fs::remove_file(&store.path).unwrap();
+        assert_eq!(saved, format!("{} aa laptop\n", "01".repeat(32)));
+
+        assert!(matches!(
+            store.identify("laptop", Some("bb")),
+            Err(HandshakeError::IdentityChanged(_))
+        ));
+    }
+
+    #[test]
+    fn reads_back_lines() {
+        for peer in [
+            peer("living room", 0xAB, Some("aa:bb")),
+            peer("pc", 0, None),
+        ] {
+            let read = Peer::from_line(&peer.to_line()).unwrap();
+            assert_eq!(read.name, peer.name);
+            assert_eq!(read.key, peer.key);
+            assert_eq!(read.fingerprint, peer.fingerprint);
+        }
+
+        let key = "00".repeat(32);
+        for malformed in [
+            "".to_owned(),
+            format!("{} -", key),
+            format!("{}0 - pc", key),
+            format!("{} - pc", "00".repeat(31)),
+            format!("{}zz - pc", "00".repeat(31)),
+        ] {
+            assert!(Peer::from_line(&malformed).is_none(), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn replaces_on_insert() {
+        let mut store = store(
+            "insert",
+            vec![peer("laptop", 1, Some("aa")), peer("desktop", 2, None)],
+        );
+        store.insert(peer("renamed", 3, Some("aa")));
+        store.insert(peer("desktop", 4, None));
+
+        let names: Vec<_> = store.peers.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["renamed", "desktop"]);
+        assert_eq!(store.get("desktop").unwrap().key, [4; 32]);
+    }
+}
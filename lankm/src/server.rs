@@ -0,0 +1,643 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{self, IpAddr, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::KeyboardLayout;
+use crate::device_filter::DeviceFilters;
+use crate::event::{
+    self, Capabilities, DecodeError, Event, HandshakeError, Hello, InputState, KeyEvent,
+    KeyEventKind, KeysymEvent, Message, MessageReader, Modifiers, PointerEvent, PressedKeys,
+    UsagePage, HID_CAPS_LOCK, HID_NUM_LOCK, HID_SCROLL_LOCK,
+};
+use crate::hotkey::{HotkeyAction, HotkeyBinding};
+use crate::input_capture;
+use crate::layout::{self, Focus, FocusTracker, Layout};
+use crate::pairing;
+use crate::peers::{self, PeerStore};
+use crate::tls::{self, Identity, IdentityPaths, Stream};
+
+/// Name the server certificate is stored under in the config directory
+pub const IDENTITY_NAME: &str = "server";
+
+pub struct ServerOptions {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub layout: Layout,
+    pub hotkeys: Vec<HotkeyBinding>,
+    pub allow_plaintext: bool,
+    pub heartbeat: Heartbeat,
+    pub devices: DeviceFilters,
+    /// Layout keysyms are resolved with instead of the configured one
+    pub keyboard_layout: Option<KeyboardLayout>,
+    pub identity: IdentityPaths,
+}
+
+/// Loads the config again, returning the options it gives now
+pub type Reload = dyn Fn() -> Result<ServerOptions, String> + Send + Sync;
+
+#[derive(Copy, Clone)]
+pub struct Heartbeat {
+    /// How often clients are pinged
+    pub interval: Duration,
+    /// How long a client may go without answering
+    pub timeout: Duration,
+}
+
+struct Client {
+    sender: mpsc::Sender<Message>,
+    caps: Capabilities,
+    /// What the client holds of what we sent it
+    pressed: PressedKeys,
+}
+
+/// Changes a frontend like the GUI may want to show, printed to stdout as
+/// `status: ...` lines
+enum Status<'a> {
+    Connected(&'a str),
+    Disconnected(&'a str),
+    Focus(&'a Focus),
+}
+
+impl fmt::Display for Status<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Connected(name) => write!(f, "connected {}", name),
+            Status::Disconnected(name) => write!(f, "disconnected {}", name),
+            Status::Focus(focus) => write!(f, "focus {}", focus),
+        }
+    }
+}
+
+fn report(status: Status) {
+    println!("status: {}", status);
+}
+
+/// Reads the commands a frontend may send on stdin, one per line. For now
+/// that's `text TEXT`, typing TEXT on the focused client. `\n`, `\t` and
+/// `\\` in it stand for a newline, a tab and a backslash.
+fn read_commands(state: Arc<Mutex<ServerState>>, reload: Arc<Reload>) {
+    for line in io::stdin().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Could not read commands: {}", e);
+                return;
+            }
+        };
+
+        match line.split_once(' ') {
+            Some(("text", text)) => {
+                state
+                    .lock()
+                    .unwrap()
+                    .handle_event(Event::Text(unescape(text)));
+            }
+            _ if line.trim() == "reload" => reload_config(&*reload),
+            _ if line.trim().is_empty() => {}
+            _ => log::warn!("Unknown command: {}", line),
+        }
+    }
+}
+
+/// Applies the hotkeys and device filters of the config as it is now,
+/// without touching connections. An invalid config changes nothing.
+fn reload_config(reload: &Reload) {
+    match reload() {
+        Ok(options) => {
+            input_capture::set_hotkeys(options.hotkeys);
+            input_capture::set_device_filters(options.devices);
+            log::info!("Reloaded hotkeys and device filters, other settings apply after a restart");
+        }
+        Err(e) => log::error!(
+            "Could not reload the config, keeping the current one: {}",
+            e
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn reload_on_sighup(reload: Arc<Reload>) {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            log::warn!("Can't reload the config on SIGHUP: {}", e);
+            return;
+        }
+    };
+    for _ in signals.forever() {
+        reload_config(&*reload);
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+/// State shared between the input capture callback and the client threads
+struct ServerState {
+    focus: FocusTracker,
+    clients: HashMap<String, Client>,
+    /// Modifiers and locks as the user has them, wherever the keys went
+    mods: Modifiers,
+    /// Keys held on this machine's keyboard, wherever they went
+    held: PressedKeys,
+    /// The keysym each held key was sent as, its release must match
+    keysyms_down: HashMap<u16, u32>,
+}
+
+impl ServerState {
+    fn connect(&mut self, name: &str, client: Client) {
+        self.clients.insert(name.to_owned(), client);
+        self.focus.connect(name);
+        report(Status::Connected(name));
+
+        // Whatever it still holds from before a reconnect is stale
+        self.send_to(name, Message::State(self.state_for(name)));
+    }
+
+    /// Forgets a client, giving input back to the server if the client had
+    /// it. Otherwise the local keyboard would stay dead until a hotkey.
+    fn disconnect(&mut self, name: &str) {
+        self.clients.remove(name);
+        report(Status::Disconnected(name));
+
+        if let Some(focus) = self.focus.disconnect(name) {
+            log::warn!("{} had focus, giving input back to {}", name, focus);
+            report(Status::Focus(&focus));
+            input_capture::sync_locks(self.mods & Modifiers::LOCKS);
+        }
+    }
+
+    /// What a client should hold and have toggled, the held keys are only
+    /// for the client with focus
+    fn state_for(&self, name: &str) -> InputState {
+        if self.focus.focus() == Focus::Remote(name.to_owned()) {
+            InputState {
+                keys: self.held.keys().to_vec(),
+                mods: self.mods,
+            }
+        } else {
+            InputState {
+                keys: Vec::new(),
+                mods: self.mods & Modifiers::LOCKS,
+            }
+        }
+    }
+
+    fn send_to(&mut self, name: &str, message: Message) {
+        let Some(client) = self.clients.get_mut(name) else {
+            return;
+        };
+
+        if matches!(message, Message::Pointer(_)) && !client.caps.contains(Capabilities::POINTER) {
+            return;
+        }
+        if matches!(message, Message::Text(_)) && !client.caps.contains(Capabilities::TEXT) {
+            log::warn!("{} can't type text", name);
+            return;
+        }
+        // The client repeats keys itself
+        let key = match &message {
+            Message::Key(k) | Message::Keysym(KeysymEvent { key: k, .. }) => Some(k),
+            _ => None,
+        };
+        if key.is_some_and(|k| k.kind == KeyEventKind::Repeat)
+            && !client.caps.contains(Capabilities::KEY_REPEAT)
+        {
+            return;
+        }
+
+        // Releases of keys pressed before the client got focus don't
+        // concern it
+        let held = match (&message, key) {
+            (_, Some(k)) => client.pressed.key(k),
+            (Message::Pointer(PointerEvent::Button { button, kind }), _) => {
+                client.pressed.button(*button, *kind)
+            }
+            (Message::State(state), _) => {
+                client.pressed.set_keys(&state.keys);
+                true
+            }
+            _ => true,
+        };
+        if !held {
+            return;
+        }
+
+        // A failed send means the client thread is on its way out, it
+        // unregisters the client itself
+        let _ = client.sender.send(message);
+    }
+
+    /// Releases exactly the keys and buttons a client holds, for when it
+    /// loses focus
+    fn release_held(&mut self, name: &str) {
+        let Some(client) = self.clients.get(name) else {
+            return;
+        };
+
+        for release in client.pressed.releases() {
+            match release {
+                Event::Key(k) => self.send_key(name, k),
+                Event::Pointer(p) => self.send_to(name, Message::Pointer(p)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a key as a keysym of this machine's layout if the client asked
+    /// for that. Modifiers, locks and media keys go by position, they are
+    /// the same in every layout.
+    fn send_key(&mut self, name: &str, k: KeyEvent) {
+        let wants_keysyms = self
+            .clients
+            .get(name)
+            .is_some_and(|c| c.caps.contains(Capabilities::KEYSYMS));
+        if !wants_keysyms
+            || k.page != UsagePage::Keyboard
+            || Modifiers::from_hid(k.hid).is_some()
+            || [HID_CAPS_LOCK, HID_NUM_LOCK, HID_SCROLL_LOCK].contains(&k.hid)
+        {
+            self.send_to(name, Message::Key(k));
+            return;
+        }
+
+        let keysym = match k.kind {
+            KeyEventKind::Press => {
+                let keysym = input_capture::keysym(&k);
+                if let Some(keysym) = keysym {
+                    self.keysyms_down.insert(k.hid, keysym);
+                }
+                keysym
+            }
+            KeyEventKind::Repeat => self.keysyms_down.get(&k.hid).copied(),
+            KeyEventKind::Release => self.keysyms_down.remove(&k.hid),
+        };
+        match keysym {
+            Some(keysym) => self.send_to(name, Message::Keysym(KeysymEvent { keysym, key: k })),
+            None => self.send_to(name, Message::Key(k)),
+        }
+    }
+
+    /// Decides where an input event goes, returns true if it must not reach
+    /// the local machine
+    fn handle_event(&mut self, e: Event) -> bool {
+        let old_focus = self.focus.focus();
+        if let Event::Key(k) = &e {
+            self.mods = k.mods;
+            self.held.key(k);
+        }
+
+        let new_focus = match e {
+            Event::Pointer(PointerEvent::Motion { dx, dy }) => self.focus.motion(dx, dy),
+            Event::Hotkey(HotkeyAction::Local) => self.focus.switch_local(),
+            Event::Hotkey(HotkeyAction::Client(ref name)) => {
+                let new_focus = self.focus.switch_to(name);
+                if new_focus.is_none() && old_focus != Focus::Remote(name.clone()) {
+                    log::warn!("Can't switch to {}, it's not connected", name);
+                }
+                new_focus
+            }
+            Event::Hotkey(HotkeyAction::Next) => self.focus.next(),
+            _ => None,
+        };
+
+        let Some(new_focus) = new_focus else {
+            // No focus change, forward the event if a client has focus
+            if let Focus::Remote(name) = &old_focus {
+                match e {
+                    Event::Key(k) => self.send_key(name, k),
+                    Event::Pointer(p) => self.send_to(name, Message::Pointer(p)),
+                    Event::Text(ref text) => {
+                        for chunk in text_chunks(text) {
+                            self.send_to(name, Message::Text(chunk.to_owned()));
+                        }
+                    }
+                    Event::Hotkey(_) => {}
+                }
+            } else if let Event::Text(_) = e {
+                log::warn!("Text can only be typed on clients, this machine has focus");
+            }
+
+            // Hotkeys never reach the local machine, even if there was nowhere to switch to
+            return old_focus != Focus::Local || matches!(e, Event::Hotkey(_));
+        };
+
+        log::info!("Focus moved to {}", new_focus);
+        report(Status::Focus(&new_focus));
+
+        // Focus was taken away from a client, it lets go of whatever is
+        // still held, the hotkey's modifiers included, and stops repeating
+        if let Focus::Remote(name) = &old_focus {
+            self.release_held(name);
+            self.send_to(name, Message::Leave);
+        }
+        self.keysyms_down.clear();
+
+        // Locks toggled on the old focus must stay on for the new one, and
+        // a client picks up the keys that are still held
+        match &new_focus {
+            Focus::Local => input_capture::sync_locks(self.mods & Modifiers::LOCKS),
+            Focus::Remote(name) => self.send_to(name, Message::State(self.state_for(name))),
+        }
+
+        // The event that caused the switch is never forwarded
+        true
+    }
+}
+
+/// Splits text into pieces that fit in a message
+fn text_chunks(text: &str) -> impl Iterator<Item = &str> {
+    const MAX_CHUNK: usize = 4096;
+
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        let mut end = rest.len().min(MAX_CHUNK);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+fn handshake(stream: &mut Stream, state: &Mutex<ServerState>) -> Result<Hello, HandshakeError> {
+    stream
+        .tcp()
+        .set_read_timeout(Some(crate::HANDSHAKE_TIMEOUT))?;
+
+    // Reloaded for every client, `pair` runs as a separate process
+    let mut peers = PeerStore::load(peers::CLIENTS_FILE)?;
+    let fingerprint = stream.peer_fingerprint();
+    let mut known = None;
+    let mut hello = event::server_handshake(stream, &crate::hostname(), |hello| {
+        if hello.name.is_empty() {
+            return Err("clients must have a name".to_owned());
+        }
+
+        let peer = match peers.identify(&hello.name, fingerprint.as_deref()) {
+            Ok(peer) => peer,
+            Err(HandshakeError::NotPaired(_)) => {
+                return Err(format!(
+                    "{} is not paired with this server, run `lankm-headless pair` on the server",
+                    hello.name
+                ))
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        // The name it's known by, which `known-hosts rename` may have changed
+        layout::check_client_name(&peer.name)?;
+        if state.lock().unwrap().clients.contains_key(&peer.name) {
+            return Err(format!("a client named {} is already connected", peer.name));
+        }
+
+        known = Some(peer);
+        Ok(())
+    })?;
+
+    // Set by the closure when it lets the client in
+    let peer = known.unwrap();
+    pairing::server_authenticate(stream, &peer.key)?;
+    stream.tcp().set_read_timeout(None)?;
+
+    // The client may go by another name here, see `known-hosts rename`
+    hello.name = peer.name;
+    Ok(hello)
+}
+
+/// Reads whatever the client sent since the last call without blocking,
+/// returns whether it said anything
+fn poll_client(stream: &mut Stream, reader: &mut MessageReader) -> io::Result<bool> {
+    stream.tcp().set_nonblocking(true)?;
+
+    let mut heard = false;
+    let result = loop {
+        match reader.read_from(stream) {
+            Ok(Some(Message::Pong)) => heard = true,
+            Ok(Some(m)) => {
+                heard = true;
+                log::warn!("Unexpected message from client: {:?}", m);
+            }
+            Ok(None) => break Ok(heard),
+            Err(e) if DecodeError::from_io(&e).is_some() => {
+                heard = true;
+                log::warn!("Dropping a bad message from client: {}", e);
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    stream.tcp().set_nonblocking(false)?;
+    result
+}
+
+/// Writes a message to a client. A write that times out means the client
+/// stopped reading, and with part of the message written the connection
+/// can't be used anymore either way.
+fn send(stream: &mut Stream, message: &Message) -> io::Result<()> {
+    message.write_to(stream).map_err(|e| match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            io::Error::new(io::ErrorKind::TimedOut, "the client stopped receiving")
+        }
+        _ => e,
+    })
+}
+
+/// Forwards input to a client until it goes away or stops answering pings
+fn serve_client(
+    stream: &mut Stream,
+    receiver: mpsc::Receiver<Message>,
+    heartbeat: Heartbeat,
+) -> io::Result<()> {
+    let mut reader = MessageReader::default();
+    let mut last_ping = Instant::now();
+    let mut last_heard = Instant::now();
+
+    loop {
+        let until_ping = heartbeat.interval.saturating_sub(last_ping.elapsed());
+        match receiver.recv_timeout(until_ping) {
+            Ok(message) => send(stream, &message)?,
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            // The server is shutting down
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        if last_ping.elapsed() < heartbeat.interval {
+            continue;
+        }
+
+        if poll_client(stream, &mut reader)? {
+            last_heard = Instant::now();
+        }
+        if last_heard.elapsed() > heartbeat.timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "missed the client's heartbeat",
+            ));
+        }
+
+        send(stream, &Message::Ping)?;
+        last_ping = Instant::now();
+    }
+}
+
+fn client_thread(
+    tcp: TcpStream,
+    state: Arc<Mutex<ServerState>>,
+    tls_config: Arc<rustls::ServerConfig>,
+    allow_plaintext: bool,
+    heartbeat: Heartbeat,
+) {
+    let addr = match tcp.peer_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            log::error!("Could not get the address of a new client: {}", e);
+            return;
+        }
+    };
+
+    let accepted = tcp
+        .set_read_timeout(Some(crate::HANDSHAKE_TIMEOUT))
+        .and_then(|_| tls::accept(tcp, &tls_config, allow_plaintext));
+    let mut stream = match accepted {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::error!("Rejected client {}: {}", addr, e);
+            return;
+        }
+    };
+
+    let hello = match handshake(&mut stream, &state) {
+        Ok(hello) => hello,
+        Err(e) => {
+            log::error!("Rejected client {}: {}", addr, e);
+            return;
+        }
+    };
+    let name = hello.name;
+
+    let (sender, receiver) = mpsc::channel();
+    {
+        let mut state = state.lock().unwrap();
+
+        // Another client might have taken the name while we were answering
+        if state.clients.contains_key(&name) {
+            log::error!("Rejected client {}: {} is already connected", addr, name);
+            return;
+        }
+
+        state.connect(
+            &name,
+            Client {
+                sender,
+                caps: hello.caps,
+                pressed: PressedKeys::default(),
+            },
+        );
+    }
+    log::info!("client {} connected from {}", name, addr);
+    if !stream.is_encrypted() {
+        log::warn!("client {} is not using encryption", name);
+    }
+    log::debug!("Client capabilities: {:?}", hello.caps);
+
+    // A client gone from the network stops taking data, and once the send
+    // buffer is full a write would block forever without a timeout
+    let served = stream
+        .tcp()
+        .set_write_timeout(Some(heartbeat.timeout))
+        .and_then(|_| serve_client(&mut stream, receiver, heartbeat));
+    if let Err(e) = served {
+        log::error!("Lost the connection to client {}: {}", name, e);
+    }
+
+    state.lock().unwrap().disconnect(&name);
+    log::info!("client {} disconnected", name);
+}
+
+/// `reload` is called on SIGHUP and the `reload` command
+pub fn run_server(
+    options: ServerOptions,
+    reload: impl Fn() -> Result<ServerOptions, String> + Send + Sync + 'static,
+) {
+    let reload: Arc<Reload> = Arc::new(reload);
+    let identity = match Identity::load_or_generate(&options.identity) {
+        Ok(identity) => identity,
+        Err(e) => {
+            log::error!("Could not load the server certificate: {}", e);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Server fingerprint: {}", identity.fingerprint());
+    let tls_config = tls::server_config(&identity);
+
+    let state = Arc::new(Mutex::new(ServerState {
+        focus: FocusTracker::new(options.layout),
+        clients: HashMap::new(),
+        mods: Modifiers::empty(),
+        held: PressedKeys::default(),
+        keysyms_down: HashMap::new(),
+    }));
+
+    let capture_state = state.clone();
+    let locks = input_capture::init(
+        options.hotkeys,
+        &options.devices,
+        options.keyboard_layout,
+        move |e| capture_state.lock().unwrap().handle_event(e),
+    );
+    state.lock().unwrap().mods = locks;
+
+    let command_state = state.clone();
+    let command_reload = reload.clone();
+    thread::spawn(move || read_commands(command_state, command_reload));
+    #[cfg(unix)]
+    thread::spawn(move || reload_on_sighup(reload));
+
+    // TODO: Maybe handle this unwrap gracefully
+    let listener = net::TcpListener::bind((options.bind, options.port)).unwrap();
+
+    // Each client gets its own thread, so a slow handshake or a stuck
+    // connection doesn't hold up the others
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let state = state.clone();
+                let tls_config = tls_config.clone();
+                let allow_plaintext = options.allow_plaintext;
+                let heartbeat = options.heartbeat;
+                thread::spawn(move || {
+                    client_thread(stream, state, tls_config, allow_plaintext, heartbeat)
+                });
+            }
+            Err(e) => log::error!("Error accepting a client: {}", e),
+        }
+    }
+}
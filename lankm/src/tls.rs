@@ -0,0 +1,393 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, DistinguishedName, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+};
+
+use crate::event::Message;
+
+/// First byte of a TLS handshake record, a plaintext lankm client starts
+/// with a Hello frame instead
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-lankm-channel-binding";
+
+/// A connection to a peer, encrypted unless plaintext was explicitly allowed
+pub enum Stream {
+    Plain(TcpStream),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// The underlying socket, for timeouts and addresses
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(s) => s,
+            Stream::Server(s) => &s.sock,
+            Stream::Client(s) => &s.sock,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        !matches!(self, Stream::Plain(_))
+    }
+
+    /// Fingerprint of the certificate the peer presented, if any
+    pub fn peer_fingerprint(&self) -> Option<String> {
+        let certs = match self {
+            Stream::Plain(_) => None,
+            Stream::Server(s) => s.conn.peer_certificates(),
+            Stream::Client(s) => s.conn.peer_certificates(),
+        };
+        certs.and_then(|certs| certs.first()).map(fingerprint)
+    }
+
+    /// Secret unique to this TLS session, mixed into authentication so it
+    /// can't be relayed to another connection. Empty for plaintext.
+    pub fn channel_binding(&self) -> Vec<u8> {
+        let exported = match self {
+            Stream::Plain(_) => return Vec::new(),
+            Stream::Server(s) => s.conn.export_keying_material([0; 32], EXPORTER_LABEL, None),
+            Stream::Client(s) => s.conn.export_keying_material([0; 32], EXPORTER_LABEL, None),
+        };
+        // Can only fail before the handshake is done, which never happens here
+        exported.unwrap().to_vec()
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Server(s) => s.read(buf),
+            Stream::Client(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Server(s) => s.write(buf),
+            Stream::Client(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Server(s) => s.flush(),
+            Stream::Client(s) => s.flush(),
+        }
+    }
+}
+
+/// SHA-256 of a certificate, as lowercase hex
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Accepts fingerprints the way people tend to paste them, with colons or
+/// in uppercase
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_lowercase()
+}
+
+/// Where a certificate and its private key are kept
+#[derive(Clone, Debug)]
+pub struct IdentityPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl IdentityPaths {
+    /// The identity stored under `name` in the config directory
+    pub fn named(name: &str) -> Self {
+        let dir = crate::config_dir();
+        Self {
+            cert: dir.join(format!("{}.crt", name)),
+            key: dir.join(format!("{}.key", name)),
+        }
+    }
+}
+
+/// A self-signed certificate and its private key
+pub struct Identity {
+    pub cert: CertificateDer<'static>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl Identity {
+    /// Loads the identity at `paths`, generating and storing a new one on
+    /// first use
+    pub fn load_or_generate(paths: &IdentityPaths) -> io::Result<Self> {
+        let cert_path = &paths.cert;
+        let key_path = &paths.key;
+
+        if !cert_path.exists() || !key_path.exists() {
+            log::info!("Generating a new certificate at {}", cert_path.display());
+
+            let generated = rcgen::generate_simple_self_signed(vec!["lankm".to_owned()])
+                .map_err(io::Error::other)?;
+
+            for path in [cert_path, key_path] {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+            }
+            fs::write(cert_path, generated.cert.pem())?;
+            write_private(key_path, generated.key_pair.serialize_pem().as_bytes())?;
+        }
+
+        let cert = CertificateDer::from_pem_file(cert_path)
+            .map_err(|e| io::Error::other(format!("reading {}: {}", cert_path.display(), e)))?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| io::Error::other(format!("reading {}: {}", key_path.display(), e)))?;
+
+        Ok(Self { cert, key })
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.cert)
+    }
+}
+
+/// Writes a file only the current user can read
+#[cfg(unix)]
+pub fn write_private(path: &std::path::Path, contents: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+pub fn write_private(path: &std::path::Path, contents: &[u8]) -> io::Result<()> {
+    fs::write(path, contents)
+}
+
+pub fn server_config(identity: &Identity) -> Arc<ServerConfig> {
+    let verifier = SelfSignedClientVerifier {
+        provider: Arc::new(rustls::crypto::ring::default_provider()),
+    };
+
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(Arc::new(verifier))
+        .with_single_cert(vec![identity.cert.clone()], identity.key.clone_key())
+        .unwrap();
+
+    Arc::new(config)
+}
+
+/// Wraps a freshly accepted connection, refusing plaintext clients unless
+/// they are explicitly allowed
+pub fn accept(
+    tcp: TcpStream,
+    config: &Arc<ServerConfig>,
+    allow_plaintext: bool,
+) -> io::Result<Stream> {
+    let mut first_byte = [0; 1];
+    if tcp.peek(&mut first_byte)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    if first_byte[0] == TLS_HANDSHAKE_RECORD {
+        let conn = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+        let mut stream = StreamOwned::new(conn, tcp);
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+
+        Ok(Stream::Server(Box::new(stream)))
+    } else if allow_plaintext {
+        Ok(Stream::Plain(tcp))
+    } else {
+        // Tell a plaintext client why, it's expecting a lankm frame anyway
+        let reason = "plaintext connections are not allowed";
+        let _ = Message::Reject(reason.to_owned()).write_to(&mut &tcp);
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+    }
+}
+
+// Peers use self-signed certificates, so there are no certificate
+// authorities to check them against. Whether a certificate is one we know is
+// decided against the known hosts once the peer has said who it is.
+
+/// Trusts any server, unless a fingerprint was pinned
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    fingerprint: Option<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        match &self.fingerprint {
+            Some(pinned) if *pinned != actual => Err(rustls::Error::General(format!(
+                "server fingerprint {} doesn't match the pinned {}",
+                actual, pinned
+            ))),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Requires clients to present a certificate, any certificate
+#[derive(Debug)]
+struct SelfSignedClientVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for SelfSignedClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Config presenting our certificate and trusting any server, or only the
+/// one with the pinned fingerprint
+pub fn client_config(identity: &Identity, fingerprint: Option<&str>) -> Arc<ClientConfig> {
+    let verifier = PinnedServerVerifier {
+        fingerprint: fingerprint.map(normalize_fingerprint),
+        provider: Arc::new(rustls::crypto::ring::default_provider()),
+    };
+
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_client_auth_cert(vec![identity.cert.clone()], identity.key.clone_key())
+        .unwrap();
+
+    Arc::new(config)
+}
+
+/// Wraps a connection to the server, without a config the connection
+/// stays in plaintext
+pub fn connect(
+    tcp: TcpStream,
+    address: IpAddr,
+    config: Option<&Arc<ClientConfig>>,
+) -> io::Result<Stream> {
+    let Some(config) = config else {
+        return Ok(Stream::Plain(tcp));
+    };
+
+    let conn = ClientConnection::new(config.clone(), ServerName::IpAddress(address.into()))
+        .map_err(io::Error::other)?;
+    let mut stream = StreamOwned::new(conn, tcp);
+
+    // Finish the TLS handshake right away, so a bad fingerprint is reported
+    // as such and not as some later read error
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+
+    Ok(Stream::Client(Box::new(stream)))
+}
@@ -0,0 +1,380 @@
+// Resolving characters and keysyms to key presses, and keys back to
+// keysyms, through the XKB keymap of this machine. libxkbcommon is loaded at
+// runtime, lankm works without it as long as nobody sends text or uses
+// keysym mode.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{c_char, CString};
+use std::fmt;
+use std::fs;
+use std::ptr;
+
+use xkbcommon_dl::{
+    xkb_context_flags, xkb_keymap_compile_flags, xkb_rule_names, xkbcommon_option, XKB_MOD_INVALID,
+    XKB_MOD_NAME_SHIFT,
+};
+
+use crate::config::KeyboardLayout;
+
+/// XKB keycodes are Linux key codes plus 8
+const EVDEV_OFFSET: u32 = 8;
+
+/// What the AltGr key types in layouts that have one
+const ISO_LEVEL3_SHIFT: u32 = 0xfe03;
+
+/// Where Debian and friends keep the console and X keyboard layout
+const KEYBOARD_DEFAULTS: &str = "/etc/default/keyboard";
+
+/// Where systemd-localed keeps it, on Fedora, Arch and others. Newer
+/// versions write the layout to vconsole.conf too.
+const VCONSOLE_CONF: &str = "/etc/vconsole.conf";
+const XORG_KEYBOARD_CONF: &str = "/etc/X11/xorg.conf.d/00-keyboard.conf";
+
+/// A key and the modifiers to hold while pressing it
+#[derive(Copy, Clone, Debug)]
+pub struct KeyCombo {
+    /// Linux key code
+    pub key: u16,
+    pub ctrl: bool,
+    pub shift: bool,
+    /// AltGr, ISO_Level3_Shift in XKB
+    pub level3: bool,
+}
+
+/// The layout's rules, model, layout, variant and options. Unset fields are
+/// taken from the XKB_DEFAULT_* variables by libxkbcommon.
+#[derive(Default)]
+struct RuleNames {
+    model: Option<CString>,
+    layout: Option<CString>,
+    variant: Option<CString>,
+    options: Option<CString>,
+    /// Where the names come from, for the log
+    source: String,
+}
+
+impl RuleNames {
+    /// The layout set for lankm, or else the one of the session given by
+    /// XKB_DEFAULT_LAYOUT, or else the system's
+    fn configured(layout: Option<&KeyboardLayout>) -> Self {
+        if let Some(layout) = layout {
+            let mut names = Self::system().unwrap_or_default();
+            names.layout = CString::new(layout.layout.as_str()).ok();
+            names.variant = layout.variant.as_deref().and_then(|v| CString::new(v).ok());
+            names.source = "the keyboard-layout setting".to_owned();
+            return names;
+        }
+
+        if env::var_os("XKB_DEFAULT_LAYOUT").is_some() {
+            return Self {
+                source: "the XKB_DEFAULT_* variables".to_owned(),
+                ..Self::default()
+            };
+        }
+
+        Self::system().unwrap_or_else(|| {
+            log::warn!(
+                "No keyboard layout configured, using libxkbcommon's default. \
+                 Set keyboard-layout if this machine uses another one"
+            );
+            Self {
+                source: "libxkbcommon's defaults".to_owned(),
+                ..Self::default()
+            }
+        })
+    }
+
+    /// The system-wide layout, as set up by the installer or localectl
+    fn system() -> Option<Self> {
+        [KEYBOARD_DEFAULTS, VCONSOLE_CONF, XORG_KEYBOARD_CONF]
+            .into_iter()
+            .find_map(|path| {
+                let contents = fs::read_to_string(path).ok()?;
+                let mut names = if path == XORG_KEYBOARD_CONF {
+                    Self::parse_xorg_conf(&contents)
+                } else {
+                    Self::parse_shell_vars(&contents)
+                };
+                names.layout.as_ref()?;
+                names.source = path.to_owned();
+                Some(names)
+            })
+    }
+
+    /// `XKBLAYOUT="de"` lines
+    fn parse_shell_vars(contents: &str) -> Self {
+        let mut names = Self::default();
+        for line in contents.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            names.set(key.trim(), value.trim().trim_matches('"'));
+        }
+        names
+    }
+
+    /// `Option "XkbLayout" "de"` lines
+    fn parse_xorg_conf(contents: &str) -> Self {
+        let mut names = Self::default();
+        for line in contents.lines() {
+            let mut words = line.split('"').skip(1).step_by(2);
+            if line.trim_start().starts_with("Option") {
+                if let (Some(key), Some(value)) = (words.next(), words.next()) {
+                    names.set(&key.to_uppercase(), value);
+                }
+            }
+        }
+        names
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        let value = Some(value)
+            .filter(|v| !v.is_empty())
+            .and_then(|v| CString::new(v).ok());
+        match key {
+            "XKBMODEL" => self.model = value,
+            "XKBLAYOUT" => self.layout = value,
+            "XKBVARIANT" => self.variant = value,
+            "XKBOPTIONS" => self.options = value,
+            _ => {}
+        }
+    }
+
+    fn raw(&self) -> xkb_rule_names {
+        let ptr = |name: &Option<CString>| name.as_ref().map_or(ptr::null(), |n| n.as_ptr());
+
+        xkb_rule_names {
+            rules: ptr::null(),
+            model: ptr(&self.model),
+            layout: ptr(&self.layout),
+            variant: ptr(&self.variant),
+            options: ptr(&self.options),
+        }
+    }
+}
+
+impl fmt::Display for RuleNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |name: &Option<CString>| {
+            name.as_ref()
+                .map_or("default".into(), |n| n.to_string_lossy().into_owned())
+        };
+
+        write!(f, "{}", name(&self.layout))?;
+        if self.variant.is_some() {
+            write!(f, "({})", name(&self.variant))?;
+        }
+        Ok(())
+    }
+}
+
+/// What each character and keysym of the first layout is typed with
+pub struct Keymap {
+    chars: HashMap<char, KeyCombo>,
+    keysyms: HashMap<u32, KeyCombo>,
+    /// The keysym of each key at each level, keyed by key, shift, level3
+    levels: HashMap<(u16, bool, bool), u32>,
+    altgr: bool,
+}
+
+impl Keymap {
+    /// Compiles `layout`, or else the keymap this machine is configured
+    /// with. That's the system-wide one unless the XKB_DEFAULT_* variables
+    /// say otherwise, a layout picked in the desktop's settings is unknown.
+    pub fn load(layout: Option<&KeyboardLayout>) -> Result<Self, String> {
+        let xkb = xkbcommon_option().ok_or("libxkbcommon is not installed")?;
+        let names = RuleNames::configured(layout);
+
+        unsafe {
+            let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+            if context.is_null() {
+                return Err("could not create an XKB context".to_owned());
+            }
+            let keymap = (xkb.xkb_keymap_new_from_names)(
+                context,
+                &names.raw(),
+                xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+            );
+            (xkb.xkb_context_unref)(context);
+            if keymap.is_null() {
+                return Err(format!("could not compile the {} keymap", names));
+            }
+            let state = (xkb.xkb_state_new)(keymap);
+            if state.is_null() {
+                (xkb.xkb_keymap_unref)(keymap);
+                return Err("could not create an XKB state".to_owned());
+            }
+
+            let mod_mask = |name: &[u8]| match (xkb.xkb_keymap_mod_get_index)(
+                keymap,
+                name.as_ptr() as *const c_char,
+            ) {
+                XKB_MOD_INVALID => None,
+                index => Some(1 << index),
+            };
+            let shift = mod_mask(XKB_MOD_NAME_SHIFT);
+            // Where ISO_Level3_Shift ends up in every layout that has it
+            let level3 = mod_mask(b"Mod5\0");
+
+            // Plain keys win over shifted ones, shifted ones over AltGr
+            let mut levels = vec![(0, false, false)];
+            levels.extend(shift.map(|shift| (shift, true, false)));
+            levels.extend(level3.map(|level3| (level3, false, true)));
+            if let (Some(shift), Some(level3)) = (shift, level3) {
+                levels.push((shift | level3, true, true));
+            }
+
+            let mut chars = HashMap::new();
+            let mut keysyms = HashMap::new();
+            let mut key_levels = HashMap::new();
+            let min = (xkb.xkb_keymap_min_keycode)(keymap).max(EVDEV_OFFSET);
+            let max = (xkb.xkb_keymap_max_keycode)(keymap);
+            for (mask, shift, level3) in levels {
+                (xkb.xkb_state_update_mask)(state, mask, 0, 0, 0, 0, 0);
+                for keycode in min..=max {
+                    let Ok(key) = u16::try_from(keycode - EVDEV_OFFSET) else {
+                        continue;
+                    };
+                    let combo = KeyCombo {
+                        key,
+                        ctrl: false,
+                        shift,
+                        level3,
+                    };
+
+                    let c = (xkb.xkb_state_key_get_utf32)(state, keycode);
+                    if let Some(c) = char::from_u32(c).filter(|&c| c != '\0') {
+                        chars.entry(c).or_insert(combo);
+                    }
+                    // 0 is NoSymbol
+                    let keysym = (xkb.xkb_state_key_get_one_sym)(state, keycode);
+                    if keysym != 0 {
+                        keysyms.entry(keysym).or_insert(combo);
+                        key_levels.insert((key, shift, level3), keysym);
+                    }
+                }
+            }
+
+            (xkb.xkb_state_unref)(state);
+            (xkb.xkb_keymap_unref)(keymap);
+
+            // Layouts without AltGr have a plain right Alt key
+            let altgr = key_levels.get(&(evdev::Key::KEY_RIGHTALT.code(), false, false))
+                == Some(&ISO_LEVEL3_SHIFT);
+
+            log::info!(
+                "Loaded the {} keymap from {}, {} characters, {} keysyms",
+                names,
+                names.source,
+                chars.len(),
+                keysyms.len()
+            );
+            Ok(Self {
+                chars,
+                keysyms,
+                levels: key_levels,
+                altgr,
+            })
+        }
+    }
+
+    /// The key typing `c` in this layout
+    pub fn lookup(&self, c: char) -> Option<KeyCombo> {
+        // XKB calls the Enter key a carriage return
+        let c = if c == '\n' { '\r' } else { c };
+
+        self.chars.get(&c).copied()
+    }
+
+    /// The key typing `keysym` in this layout
+    pub fn keysym_combo(&self, keysym: u32) -> Option<KeyCombo> {
+        self.keysyms.get(&keysym).copied()
+    }
+
+    /// The keysym `key` types with the given modifiers held
+    pub fn keysym(&self, key: u16, shift: bool, level3: bool) -> Option<u32> {
+        self.levels
+            .get(&(key, shift, level3 && self.altgr))
+            .copied()
+    }
+
+    /// Whether the right Alt key is AltGr, selecting the third level
+    pub fn has_altgr(&self) -> bool {
+        self.altgr
+    }
+
+    /// The keys typing `c`. Characters the layout doesn't have are typed as
+    /// Ctrl+Shift+U, their code point in hex and a space, which GTK and
+    /// IBus turn back into the character.
+    pub fn type_char(&self, c: char) -> Option<Vec<KeyCombo>> {
+        if let Some(combo) = self.lookup(c) {
+            return Some(vec![combo]);
+        }
+
+        let u = self.lookup('u')?;
+        let mut combos = vec![KeyCombo {
+            ctrl: true,
+            shift: true,
+            ..u
+        }];
+        for digit in format!("{:x}", c as u32).chars() {
+            combos.push(self.lookup(digit)?);
+        }
+        combos.push(self.lookup(' ')?);
+
+        Some(combos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &Option<CString>) -> Option<&str> {
+        name.as_ref().map(|n| n.to_str().unwrap())
+    }
+
+    #[test]
+    fn reads_debian_and_vconsole_files() {
+        let names = RuleNames::parse_shell_vars(
+            "# KEYBOARD CONFIGURATION FILE\n\
+             XKBMODEL=\"pc105\"\n\
+             XKBLAYOUT=\"de\"\n\
+             XKBVARIANT=\"nodeadkeys\"\n\
+             XKBOPTIONS=\"\"\n\
+             KEYMAP=de-latin1\n",
+        );
+        assert_eq!(name(&names.model), Some("pc105"));
+        assert_eq!(name(&names.layout), Some("de"));
+        assert_eq!(name(&names.variant), Some("nodeadkeys"));
+        assert_eq!(name(&names.options), None);
+    }
+
+    #[test]
+    fn reads_xorg_keyboard_conf() {
+        let names = RuleNames::parse_xorg_conf(
+            "# Written by systemd-localed(8)\n\
+             Section \"InputClass\"\n\
+             \x20       Identifier \"system-keyboard\"\n\
+             \x20       MatchIsKeyboard \"on\"\n\
+             \x20       Option \"XkbLayout\" \"fr\"\n\
+             \x20       Option \"XkbVariant\" \"azerty\"\n\
+             \x20       Option \"XkbOptions\" \"compose:ralt\"\n\
+             EndSection\n",
+        );
+        assert_eq!(name(&names.layout), Some("fr"));
+        assert_eq!(name(&names.variant), Some("azerty"));
+        assert_eq!(name(&names.options), Some("compose:ralt"));
+        assert_eq!(name(&names.model), None);
+    }
+
+    #[test]
+    fn setting_wins() {
+        let layout = "ch(fr)".parse().unwrap();
+        let names = RuleNames::configured(Some(&layout));
+        assert_eq!(name(&names.layout), Some("ch"));
+        assert_eq!(name(&names.variant), Some("fr"));
+        assert_eq!(names.to_string(), "ch(fr)");
+    }
+}